@@ -1,7 +1,89 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 pub mod model;
+pub mod sequencer;
 pub mod sqs;
 
-#[derive(Debug, Clone, Default)]
+/// SQS caps a single receive at 10 messages.
+pub const MAX_MESSAGES_PER_RECEIVE: i32 = 10;
+/// SQS caps long polling at 20 seconds.
+pub const MAX_WAIT_TIME: Duration = Duration::from_secs(20);
+/// SQS caps visibility timeouts at 12 hours.
+pub const MAX_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+/// SQS allows at most 10 message attributes per message.
+pub const MAX_MESSAGE_ATTRIBUTES: usize = 10;
+/// Longest error description attached to a dead lettered message.
+pub const MAX_ERROR_DESCRIPTION: usize = 4096;
+pub const ERROR_DESCRIPTION_ATTRIBUTE: &str = "ErrorDescription";
+pub const SOURCE_QUEUE_ATTRIBUTE: &str = "SourceQueueUrl";
+
+#[derive(Debug, Clone)]
 pub struct SqsEventOptions {
     pub queue_name: String,
+    /// Used as is instead of resolving `queue_name`, handy for queues in other accounts.
+    pub queue_url: Option<String>,
+    /// The queue is subscribed to SNS with raw message delivery, so bodies are never wrapped.
+    pub raw_message_delivery: bool,
+    /// Long poll wait for the first receive of each poll.
+    pub wait_time: Duration,
+    /// Messages per receive call, between 1 and 10.
+    pub max_messages: i32,
+    /// Receive calls per poll, later receives don't wait and stop early once the queue is drained.
+    pub receives_per_poll: usize,
+    /// Overrides the queue's default visibility timeout for received messages.
+    pub visibility_timeout: Option<Duration>,
+    pub message_attribute_names: Vec<String>,
+    /// How often in flight messages get their visibility extended, disabled when unset.
+    pub heartbeat_interval: Option<Duration>,
+    /// How long a failed message stays hidden before it is retried, doubled on every receive.
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+    /// Receives after which a failing message is moved to `dead_letter_queue_url`.
+    pub max_receive_count: Option<u32>,
+    pub dead_letter_queue_url: Option<String>,
+    /// File the latest committed sequencer per key is kept in, only kept in memory when unset.
+    pub sequencer_cache: Option<PathBuf>,
+    /// Keys remembered by the sequencer cache before the least recently updated are dropped.
+    pub sequencer_cache_capacity: usize,
+}
+
+impl SqsEventOptions {
+    /// What each heartbeat extends visibility to, the configured timeout or three missed beats.
+    pub fn heartbeat_visibility_timeout(&self) -> Duration {
+        self.visibility_timeout
+            .or(self.heartbeat_interval.map(|every| every * 3))
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// How long a message that failed on its `receive_count`th receive is hidden for.
+    pub fn retry_visibility_timeout(&self, receive_count: u32) -> Duration {
+        let doublings = receive_count.saturating_sub(1).min(31);
+        self.retry_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_retry_backoff)
+            .min(MAX_VISIBILITY_TIMEOUT)
+    }
+}
+
+impl Default for SqsEventOptions {
+    fn default() -> Self {
+        Self {
+            queue_name: String::new(),
+            queue_url: None,
+            raw_message_delivery: false,
+            wait_time: MAX_WAIT_TIME,
+            max_messages: MAX_MESSAGES_PER_RECEIVE,
+            receives_per_poll: 1,
+            visibility_timeout: None,
+            message_attribute_names: vec![],
+            heartbeat_interval: None,
+            retry_backoff: Duration::from_secs(30),
+            max_retry_backoff: Duration::from_secs(15 * 60),
+            max_receive_count: None,
+            dead_letter_queue_url: None,
+            sequencer_cache: None,
+            sequencer_cache_capacity: 100_000,
+        }
+    }
 }
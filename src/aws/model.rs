@@ -1,71 +1,507 @@
-use serde::Deserialize;
+use std::fmt;
 
-#[derive(Deserialize)]
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use object_store::path::Path;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Deserializer};
+
+use crate::{AckToken, FileEvent, FileEventKind};
+
+pub const TEST_EVENT: &str = "s3:TestEvent";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct RestoreEventData {
     pub lifecycle_restoration_expiry_time: String,
     pub lifecycle_restore_storage_class: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct GlacierEventData {
     pub restore_event_data: RestoreEventData,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct Object {
+    #[serde(deserialize_with = "deserialize_key")]
     pub key: String,
-    pub size: String,
-    pub e_tag: String,
-    pub version_id: String,
-    pub sequencer: String,
+    pub size: Option<u64>,
+    pub e_tag: Option<String>,
+    pub version_id: Option<String>,
+    pub sequencer: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct Bucket {
     pub name: String,
-    pub owner_identity: UserId,
+    pub owner_identity: Option<UserId>,
     pub arn: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct S3 {
-    pub s3schema_version: String,
+    pub s3_schema_version: String,
     pub configuration_id: String,
     pub bucket: Bucket,
     pub object: Object,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct ResponseElements {
+    #[serde(rename = "x-amz-request-id")]
     pub x_amz_request_id: String,
-    pub x_amz_id_2: String,
+    /// Not sent by MinIO.
+    #[serde(rename = "x-amz-id-2")]
+    pub x_amz_id_2: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct RequestParameters {
-    pub source_ipaddress: String,
+    #[serde(rename = "sourceIPAddress")]
+    pub source_ip_address: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct UserId {
     pub principal_id: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct Event {
     pub event_version: String,
     pub event_source: String,
     pub aws_region: String,
     pub event_time: String,
     pub event_name: String,
-    pub user_identity: UserId,
-    pub request_parameters: RequestParameters,
-    pub response_elements: ResponseElements,
+    pub user_identity: Option<UserId>,
+    pub request_parameters: Option<RequestParameters>,
+    pub response_elements: Option<ResponseElements>,
     pub s3: S3,
-    pub glacier_event_data: GlacierEventData,
+    pub glacier_event_data: Option<GlacierEventData>,
+}
+
+impl Event {
+    pub fn family(&self) -> EventFamily {
+        EventFamily::from_event_name(&self.event_name)
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct SqsEvent {
+    #[serde(rename = "Records")]
     pub records: Vec<Event>,
 }
+
+/// Sent by S3 once when a notification configuration is created on a bucket.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct TestEvent {
+    pub service: String,
+    pub event: String,
+    pub time: String,
+    pub bucket: String,
+    pub request_id: String,
+    pub host_id: String,
+}
+
+/// The top level event name prefix, e.g. `ObjectCreated` in `ObjectCreated:Put`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFamily {
+    ObjectCreated,
+    ObjectRemoved,
+    ObjectRestore,
+    ObjectTagging,
+    ObjectAcl,
+    Replication,
+    LifecycleExpiration,
+    LifecycleTransition,
+    IntelligentTiering,
+    ReducedRedundancyLostObject,
+    Other,
+}
+
+impl EventFamily {
+    pub fn from_event_name(name: &str) -> Self {
+        let name = name.strip_prefix("s3:").unwrap_or(name);
+        match name.split(':').next().unwrap_or_default() {
+            "ObjectCreated" => Self::ObjectCreated,
+            "ObjectRemoved" => Self::ObjectRemoved,
+            "ObjectRestore" => Self::ObjectRestore,
+            "ObjectTagging" => Self::ObjectTagging,
+            "ObjectAcl" => Self::ObjectAcl,
+            "Replication" => Self::Replication,
+            "LifecycleExpiration" => Self::LifecycleExpiration,
+            "LifecycleTransition" => Self::LifecycleTransition,
+            "IntelligentTiering" => Self::IntelligentTiering,
+            "ReducedRedundancyLostObject" => Self::ReducedRedundancyLostObject,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum S3Notification {
+    Records(SqsEvent),
+    Test(TestEvent),
+}
+
+impl S3Notification {
+    pub fn parse(body: &str) -> Result<Self> {
+        Self::from_value(serde_json::from_str(body)?, body)
+    }
+
+    fn from_value(value: serde_json::Value, body: &str) -> Result<Self> {
+        if value.get("Records").is_some() {
+            Ok(Self::Records(serde_json::from_value(value)?))
+        } else if value.get("Event").and_then(|e| e.as_str()) == Some(TEST_EVENT) {
+            Ok(Self::Test(serde_json::from_value(value)?))
+        } else {
+            Err(anyhow!("Unrecognized S3 notification: {}", Truncated(body)))
+        }
+    }
+
+    pub fn records(self) -> Vec<Event> {
+        match self {
+            Self::Records(event) => event.records,
+            Self::Test(_) => vec![],
+        }
+    }
+}
+
+/// What SNS delivers to a subscribed queue when raw message delivery is off.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnsEnvelope {
+    #[serde(rename = "Type")]
+    pub message_type: String,
+    pub message_id: String,
+    pub topic_arn: String,
+    pub message: String,
+    pub timestamp: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EventBridgeBucket {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct EventBridgeObject {
+    // Unlike bucket notifications, EventBridge does not encode object keys
+    pub key: String,
+    pub size: Option<u64>,
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+    pub sequencer: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct EventBridgeDetail {
+    pub bucket: EventBridgeBucket,
+    pub object: EventBridgeObject,
+    pub reason: Option<String>,
+    pub deletion_type: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct EventBridgeEvent {
+    pub id: String,
+    pub detail_type: String,
+    pub source: String,
+    pub time: String,
+    pub region: String,
+    pub detail: EventBridgeDetail,
+}
+
+impl EventBridgeEvent {
+    /// Builds a bucket notification style name, e.g. `Object Created` with reason `PutObject`
+    /// becomes `ObjectCreated:PutObject`.
+    pub fn event_name(&self) -> String {
+        let family = match self.detail_type.as_str() {
+            "Object Created" => "ObjectCreated",
+            "Object Deleted" => "ObjectRemoved",
+            "Object Restore Initiated" | "Object Restore Completed" | "Object Restore Expired" => "ObjectRestore",
+            "Object Tags Added" | "Object Tags Deleted" => "ObjectTagging",
+            "Object ACL Updated" => "ObjectAcl",
+            "Object Storage Class Changed" => "LifecycleTransition",
+            "Object Access Tier Changed" => "IntelligentTiering",
+            other => other,
+        };
+        let reason = self
+            .detail
+            .deletion_type
+            .as_deref()
+            .or(self.detail.reason.as_deref())
+            .unwrap_or_default()
+            .replace(' ', "");
+        format!("{}:{}", family, reason)
+    }
+}
+
+/// An object level event, normalized from whichever shape the message arrived in.
+#[derive(Debug, Clone)]
+pub struct ObjectNotification {
+    pub event_name: String,
+    pub event_time: String,
+    pub bucket: String,
+    pub object: Object,
+}
+
+impl ObjectNotification {
+    pub fn family(&self) -> EventFamily {
+        EventFamily::from_event_name(&self.event_name)
+    }
+
+    /// Whether an object was created or removed, the only events we ingest.
+    pub fn is_object_change(&self) -> bool {
+        matches!(self.family(), EventFamily::ObjectCreated | EventFamily::ObjectRemoved)
+    }
+
+    /// A file event for `bucket/key`, coming from `source`.
+    pub fn into_file_event(self, source: &str, ack_token: AckToken) -> Result<FileEvent> {
+        let kind = match self.family() {
+            EventFamily::ObjectRemoved => FileEventKind::Removed,
+            _ => FileEventKind::Created,
+        };
+        let ObjectNotification { event_name, event_time, bucket, object } = self;
+        let path = Path::parse(format!("{}/{}", bucket, object.key))?;
+        Ok(FileEvent {
+            kind,
+            event_name: Some(event_name),
+            size: object.size.map(|size| size as usize),
+            e_tag: object.e_tag,
+            version: object.version_id,
+            event_time: DateTime::parse_from_rfc3339(&event_time)
+                .ok()
+                .map(|t| t.with_timezone(&Utc)),
+            ack_token,
+            ..FileEvent::new(path, source)
+        })
+    }
+}
+
+impl From<Event> for ObjectNotification {
+    fn from(event: Event) -> Self {
+        Self {
+            event_name: event.event_name,
+            event_time: event.event_time,
+            bucket: event.s3.bucket.name,
+            object: event.s3.object,
+        }
+    }
+}
+
+impl From<EventBridgeEvent> for ObjectNotification {
+    fn from(event: EventBridgeEvent) -> Self {
+        let event_name = event.event_name();
+        let EventBridgeObject { key, size, etag, version_id, sequencer } = event.detail.object;
+        Self {
+            event_name,
+            event_time: event.time,
+            bucket: event.detail.bucket.name,
+            object: Object {
+                key,
+                size,
+                e_tag: etag,
+                version_id,
+                sequencer,
+            },
+        }
+    }
+}
+
+/// Any SQS message body we know how to read. S3 can deliver straight to the queue, through an SNS
+/// topic (with or without raw message delivery) or through an EventBridge rule.
+#[derive(Debug, Clone)]
+pub enum Notification {
+    S3(S3Notification),
+    Sns(SnsEnvelope, S3Notification),
+    EventBridge(EventBridgeEvent),
+}
+
+impl Notification {
+    /// With `raw_message_delivery` every body must be a bucket notification, envelopes are errors.
+    pub fn parse(body: &str, raw_message_delivery: bool) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(body)?;
+        if raw_message_delivery {
+            return S3Notification::from_value(value, body).map(Self::S3);
+        }
+
+        if value.get("Type").and_then(|t| t.as_str()) == Some("Notification") && value.get("TopicArn").is_some() {
+            let envelope: SnsEnvelope = serde_json::from_value(value)?;
+            let inner = S3Notification::parse(&envelope.message)?;
+            Ok(Self::Sns(envelope, inner))
+        } else if value.get("detail-type").is_some() && value.get("detail").is_some() {
+            let event: EventBridgeEvent = serde_json::from_value(value)?;
+            if event.source != "aws.s3" {
+                return Err(anyhow!("Unexpected EventBridge source {}: {}", event.source, Truncated(body)));
+            }
+            Ok(Self::EventBridge(event))
+        } else {
+            S3Notification::from_value(value, body).map(Self::S3)
+        }
+    }
+
+    pub fn objects(self) -> Vec<ObjectNotification> {
+        match self {
+            Self::S3(notification) | Self::Sns(_, notification) => notification
+                .records()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            Self::EventBridge(event) => vec![event.into()],
+        }
+    }
+}
+
+/// Object keys in notifications are form encoded, `+` is a space and anything else is percent escaped.
+pub fn decode_key(key: &str) -> Result<String> {
+    let key = key.replace('+', " ");
+    Ok(percent_decode_str(&key).decode_utf8()?.into_owned())
+}
+
+fn deserialize_key<'de, D>(deserializer: D) -> Result<String, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let key = String::deserialize(deserializer)?;
+    decode_key(&key).map_err(serde::de::Error::custom)
+}
+
+/// Keeps error messages readable when a whole message body ends up in them.
+struct Truncated<'a>(&'a str);
+
+impl fmt::Display for Truncated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.char_indices().nth(256) {
+            Some((idx, _)) => write!(f, "{}...", &self.0[..idx]),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs;
+
+    use super::*;
+
+    fn parse_fixture(name: &str) -> Result<S3Notification> {
+        S3Notification::parse(&fs::read_to_string(format!("./test_files/{}", name))?)
+    }
+
+    #[test]
+    pub fn test_object_created() -> Result<()> {
+        let records = parse_fixture("s3_object_created.json")?.records();
+        assert_eq!(records.len(), 1);
+        let event = &records[0];
+        assert_eq!(event.family(), EventFamily::ObjectCreated);
+        assert_eq!(event.s3.s3_schema_version, "1.0");
+        assert_eq!(event.s3.bucket.name, "landing-bucket");
+        assert_eq!(event.s3.object.key, "year=2023/month=01/part 0000=a.parquet");
+        assert_eq!(event.s3.object.size, Some(1024));
+        assert_eq!(event.s3.object.e_tag.as_deref(), Some("d41d8cd98f00b204e9800998ecf8427e"));
+        assert_eq!(event.s3.object.version_id.as_deref(), Some("096fKKXTRTtl3on89fVO.nfljtsv6qko"));
+        assert_eq!(event.s3.object.sequencer.as_deref(), Some("0055AED6DCD90281E5"));
+        let response = event.response_elements.as_ref().unwrap();
+        assert_eq!(response.x_amz_request_id, "C3D13FE58DE4C810");
+        assert_eq!(event.request_parameters.as_ref().unwrap().source_ip_address, "127.0.0.1");
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_object_removed() -> Result<()> {
+        let records = parse_fixture("s3_object_removed.json")?.records();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|e| e.family() == EventFamily::ObjectRemoved));
+        assert_eq!(records[0].s3.object.size, None);
+        assert_eq!(records[0].s3.object.e_tag, None);
+        assert_eq!(records[0].s3.object.version_id, None);
+        assert_eq!(records[1].event_name, "ObjectRemoved:DeleteMarkerCreated");
+        assert!(records[1].s3.object.version_id.is_some());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_object_restore() -> Result<()> {
+        let records = parse_fixture("s3_object_restore.json")?.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].family(), EventFamily::ObjectRestore);
+        let glacier = records[0].glacier_event_data.as_ref().unwrap();
+        assert_eq!(glacier.restore_event_data.lifecycle_restore_storage_class, "Standard");
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_test_event() -> Result<()> {
+        match parse_fixture("s3_test_event.json")? {
+            S3Notification::Test(event) => assert_eq!(event.bucket, "landing-bucket"),
+            other => panic!("Expected a test event, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_parse_errors() {
+        assert!(S3Notification::parse("not json").is_err());
+        assert!(S3Notification::parse(r#"{"hello": "world"}"#).is_err());
+        assert!(S3Notification::parse(r#"{"Records": [{"eventName": "ObjectCreated:Put"}]}"#).is_err());
+    }
+
+    #[test]
+    pub fn test_sns_envelope() -> Result<()> {
+        let body = fs::read_to_string("./test_files/sns_s3_object_created.json")?;
+        let notification = Notification::parse(&body, false)?;
+        assert!(matches!(notification, Notification::Sns(_, _)));
+        let objects = notification.objects();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].family(), EventFamily::ObjectCreated);
+        assert_eq!(objects[0].bucket, "landing-bucket");
+        assert_eq!(objects[0].object.key, "year=2023/month=01/part 0000=a.parquet");
+
+        assert!(Notification::parse(&body, true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_eventbridge_event() -> Result<()> {
+        let body = fs::read_to_string("./test_files/eventbridge_object_created.json")?;
+        let objects = Notification::parse(&body, false)?.objects();
+        assert_eq!(objects.len(), 1);
+        let object = &objects[0];
+        assert_eq!(object.event_name, "ObjectCreated:PutObject");
+        assert_eq!(object.family(), EventFamily::ObjectCreated);
+        assert_eq!(object.bucket, "landing-bucket");
+        assert_eq!(object.object.key, "year=2023/month=01/part-0003.parquet");
+        assert_eq!(object.object.size, Some(4096));
+        assert_eq!(object.object.e_tag.as_deref(), Some("b1946ac92492d2347c6235b4d2611184"));
+        assert_eq!(object.object.version_id.as_deref(), Some("IYV3p45BT0ac8hjHg1houSdS1a.Mro8e"));
+        assert_eq!(object.object.sequencer.as_deref(), Some("617f08299329d189"));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_raw_notification() -> Result<()> {
+        let body = fs::read_to_string("./test_files/s3_object_created.json")?;
+        assert!(matches!(Notification::parse(&body, false)?, Notification::S3(_)));
+        assert_eq!(Notification::parse(&body, true)?.objects().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_decode_key() -> Result<()> {
+        assert_eq!(decode_key("a+b%2Bc%3D%C3%A9")?, "a b+c=é");
+        assert_eq!(decode_key("plain/key.parquet")?, "plain/key.parquet");
+        Ok(())
+    }
+}
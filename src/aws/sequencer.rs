@@ -0,0 +1,167 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::state;
+
+/// Orders two S3 sequencers for the same key. They are hex strings of varying length, so as AWS
+/// describes the shorter one is right padded with zeros before comparing.
+pub fn compare_sequencers(a: &str, b: &str) -> Ordering {
+    let width = a.len().max(b.len());
+    let a = format!("{:0<width$}", a.to_ascii_uppercase(), width = width);
+    let b = format!("{:0<width$}", b.to_ascii_uppercase(), width = width);
+    a.cmp(&b)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    sequencer: String,
+    touched: u64,
+}
+
+/// The newest sequencer committed for each key. Holds at most `capacity` keys, dropping the least
+/// recently updated ones first, and can be saved to a file so it survives restarts.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SequencerCache {
+    #[serde(skip)]
+    capacity: usize,
+    #[serde(skip)]
+    location: Option<PathBuf>,
+    #[serde(skip)]
+    dirty: bool,
+    clock: u64,
+    entries: HashMap<String, Entry>,
+}
+
+impl SequencerCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Loads the cache previously saved at `location`, later calls to [Self::save] write back to it.
+    pub fn load(location: &Path, capacity: usize) -> Result<Self> {
+        let mut cache: Self = state::load(location)?;
+        cache.capacity = capacity;
+        cache.location = Some(location.to_path_buf());
+        cache.evict();
+        Ok(cache)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|e| e.sequencer.as_str())
+    }
+
+    /// Whether `sequencer` is newer than anything committed for `key`.
+    pub fn is_newer(&self, key: &str, sequencer: &str) -> bool {
+        self.get(key)
+            .map_or(true, |latest| compare_sequencers(sequencer, latest) == Ordering::Greater)
+    }
+
+    /// Records `sequencer` as committed for `key`, older sequencers are ignored.
+    pub fn record(&mut self, key: &str, sequencer: &str) {
+        if !self.is_newer(key, sequencer) {
+            return;
+        }
+        self.clock += 1;
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                sequencer: sequencer.to_string(),
+                touched: self.clock,
+            },
+        );
+        self.dirty = true;
+        self.evict();
+    }
+
+    /// Writes the cache out if it was loaded from a file and has changed since.
+    pub fn save(&mut self) -> Result<()> {
+        if let (Some(location), true) = (&self.location, self.dirty) {
+            state::store(location, self)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict(&mut self) {
+        if self.entries.len() <= self.capacity {
+            return;
+        }
+        // Trim a little below capacity so we aren't sorting on every insert once full
+        let keep = self.capacity - self.capacity / 10;
+        let mut touched = self.entries.values().map(|e| e.touched).collect::<Vec<_>>();
+        touched.sort_unstable_by(|a, b| b.cmp(a));
+        let oldest_kept = touched.get(keep.saturating_sub(1)).copied().unwrap_or(u64::MAX);
+        self.entries.retain(|_, e| e.touched >= oldest_kept);
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_compare_sequencers() {
+        assert_eq!(compare_sequencers("0055AED6DCD90281E5", "0055AED6DCD9028A21"), Ordering::Less);
+        assert_eq!(compare_sequencers("0055AED6DCD9028A21", "0055aed6dcd9028a21"), Ordering::Equal);
+        // The shorter sequencer is padded on the right
+        assert_eq!(compare_sequencers("0055AED6DCD902", "0055AED6DCD90281E5"), Ordering::Less);
+        assert_eq!(compare_sequencers("0056", "0055AED6DCD90281E5"), Ordering::Greater);
+        assert_eq!(compare_sequencers("FF", "FF00"), Ordering::Equal);
+        assert_eq!(compare_sequencers("FF", "0100"), Ordering::Greater);
+    }
+
+    #[test]
+    pub fn test_cache() {
+        let mut cache = SequencerCache::new(10);
+        assert!(cache.is_newer("bucket/a", "0055AED6DCD90281E5"));
+        cache.record("bucket/a", "0055AED6DCD90281E5");
+        assert!(!cache.is_newer("bucket/a", "0055AED6DCD90281E5"));
+        assert!(!cache.is_newer("bucket/a", "0055AED6DCD90281E0"));
+        assert!(cache.is_newer("bucket/a", "0055AED6DCD9028A21"));
+
+        cache.record("bucket/a", "0055AED6DCD90281E0");
+        assert_eq!(cache.get("bucket/a"), Some("0055AED6DCD90281E5"));
+    }
+
+    #[test]
+    pub fn test_cache_eviction() {
+        let mut cache = SequencerCache::new(10);
+        for i in 0..25 {
+            cache.record(&format!("bucket/{}", i), "01");
+        }
+        assert!(cache.len() <= 10);
+        assert_eq!(cache.get("bucket/24"), Some("01"));
+        assert_eq!(cache.get("bucket/0"), None);
+    }
+
+    #[test]
+    pub fn test_cache_persistence() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let location = dir.path().join("sequencers.json");
+
+        let mut cache = SequencerCache::load(&location, 10)?;
+        assert!(cache.is_empty());
+        cache.record("bucket/a", "0055AED6DCD90281E5");
+        cache.save()?;
+
+        let cache = SequencerCache::load(&location, 10)?;
+        assert_eq!(cache.get("bucket/a"), Some("0055AED6DCD90281E5"));
+        Ok(())
+    }
+}
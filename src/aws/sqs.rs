@@ -1,39 +1,552 @@
-use anyhow::Result;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
 use aws_sdk_sqs::Client;
-use object_store::path::Path;
+use aws_sdk_sqs::model::{Message, MessageAttributeValue, MessageSystemAttributeName, QueueAttributeName};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant};
+use tracing::{debug, error, warn};
 
-use crate::FileEvents;
+use crate::in_flight::InFlight;
+use crate::{AckToken, FileEvent, FileEvents};
 
+use super::sequencer::{compare_sequencers, SequencerCache};
 use super::{model::*, *};
 
+/// Keeps a message invisible while its files are being ingested, stops as soon as it is dropped.
+struct Heartbeat(JoinHandle<()>);
+
+impl Heartbeat {
+    fn start(client: Client, queue_url: String, receipt_handle: String, every: Duration, extend_to: Duration) -> Self {
+        Self(tokio::spawn(async move {
+            let mut ticks = interval_at(Instant::now() + every, every);
+            loop {
+                ticks.tick().await;
+                let extended = client
+                    .change_message_visibility()
+                    .queue_url(&queue_url)
+                    .receipt_handle(&receipt_handle)
+                    .visibility_timeout(extend_to.as_secs() as i32)
+                    .send()
+                    .await;
+                if let Err(err) = extended {
+                    warn!("Unable to extend message visibility, giving up on the heartbeat: {:?}", err);
+                    break;
+                }
+            }
+        }))
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A received message whose files have not all been committed yet.
+struct InFlightMessage {
+    message: Message,
+    receipt_handle: String,
+    // Sequencer of each key in the message, committed to the cache as the key is acked
+    sequencers: HashMap<String, String>,
+    heartbeat: Option<Heartbeat>,
+}
+
+/// The newest sequencer for each key across a batch of parsed messages.
+fn latest_sequencers(received: &[(Message, Vec<(FileEvent, Option<String>)>)]) -> HashMap<String, String> {
+    let mut latest: HashMap<String, String> = HashMap::new();
+    for (event, sequencer) in received.iter().flat_map(|(_, events)| events) {
+        let Some(sequencer) = sequencer else {
+            continue;
+        };
+        let key = event.path.to_string();
+        match latest.get(&key) {
+            Some(current) if compare_sequencers(sequencer, current) != Ordering::Greater => {}
+            _ => {
+                latest.insert(key, sequencer.clone());
+            }
+        }
+    }
+    latest
+}
+
+fn receive_count(msg: &Message) -> u32 {
+    msg.attributes()
+        .and_then(|attributes| attributes.get(&MessageSystemAttributeName::ApproximateReceiveCount))
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(1)
+}
+
+fn string_attribute(value: impl Into<String>) -> MessageAttributeValue {
+    MessageAttributeValue::builder()
+        .data_type("String")
+        .string_value(value)
+        .build()
+}
+
 pub struct SqsEvents {
     client: Client,
     opts: SqsEventOptions,
+    queue_url: String,
+    in_flight: InFlight<InFlightMessage>,
+    sequencers: SequencerCache,
 }
 
 impl SqsEvents {
-    pub fn new(client: Client, opts: SqsEventOptions) -> Self {
-        Self { client, opts }
+    pub async fn new(client: Client, mut opts: SqsEventOptions) -> Result<Self> {
+        if !(1..=MAX_MESSAGES_PER_RECEIVE).contains(&opts.max_messages) {
+            return Err(anyhow!("max_messages must be between 1 and {}, got {}", MAX_MESSAGES_PER_RECEIVE, opts.max_messages));
+        }
+        if opts.wait_time > MAX_WAIT_TIME {
+            return Err(anyhow!("wait_time can be at most {:?}, got {:?}", MAX_WAIT_TIME, opts.wait_time));
+        }
+
+        let queue_url = match &opts.queue_url {
+            Some(url) => url.clone(),
+            None => client
+                .get_queue_url()
+                .queue_name(&opts.queue_name)
+                .send()
+                .await?
+                .queue_url()
+                .ok_or_else(|| anyhow!("No queue url returned for {}", opts.queue_name))?
+                .to_string(),
+        };
+        if let Some(every) = opts.heartbeat_interval {
+            if every.is_zero() || every >= opts.heartbeat_visibility_timeout() {
+                return Err(anyhow!("heartbeat_interval must be non zero and shorter than the visibility timeout"));
+            }
+        }
+        if opts.max_receive_count.is_some() && opts.dead_letter_queue_url.is_none() {
+            return Err(anyhow!("max_receive_count needs a dead_letter_queue_url to send messages to"));
+        }
+        if opts.queue_name.is_empty() {
+            opts.queue_name = queue_url.rsplit('/').next().unwrap_or_default().to_string();
+        }
+
+        let sequencers = match &opts.sequencer_cache {
+            Some(location) => SequencerCache::load(location, opts.sequencer_cache_capacity)?,
+            None => SequencerCache::new(opts.sequencer_cache_capacity),
+        };
+
+        Ok(Self {
+            client,
+            opts,
+            queue_url,
+            in_flight: InFlight::new(),
+            sequencers,
+        })
+    }
+
+    fn queue_url(&self) -> &str {
+        &self.queue_url
+    }
+
+    fn heartbeat(&self, receipt_handle: &str) -> Option<Heartbeat> {
+        let every = self.opts.heartbeat_interval?;
+        Some(Heartbeat::start(
+            self.client.clone(),
+            self.queue_url.clone(),
+            receipt_handle.to_string(),
+            every,
+            self.opts.heartbeat_visibility_timeout(),
+        ))
+    }
+
+    async fn receive(&self) -> Result<Vec<Message>> {
+        let mut messages = vec![];
+        for attempt in 0..self.opts.receives_per_poll.max(1) {
+            // Only the first receive long polls, after that we just drain what is already there
+            let wait_time = if attempt == 0 { self.opts.wait_time } else { Duration::ZERO };
+            let mut request = self
+                .client
+                .receive_message()
+                .queue_url(self.queue_url())
+                .max_number_of_messages(self.opts.max_messages)
+                .attribute_names(QueueAttributeName::ApproximateReceiveCount)
+                .wait_time_seconds(wait_time.as_secs() as i32);
+            if let Some(timeout) = self.opts.visibility_timeout {
+                request = request.visibility_timeout(timeout.as_secs() as i32);
+            }
+            if !self.opts.message_attribute_names.is_empty() {
+                request = request.set_message_attribute_names(Some(self.opts.message_attribute_names.clone()));
+            }
+
+            let output = request.send().await?;
+            let received = output.messages().unwrap_or_default();
+            let drained = received.len() < self.opts.max_messages as usize;
+            messages.extend_from_slice(received);
+            if drained {
+                break;
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Whether `msg` has used up its attempts and should go to the dead letter queue.
+    fn exhausted(&self, msg: &Message) -> bool {
+        self.opts
+            .max_receive_count
+            .map_or(false, |max| receive_count(msg) >= max)
+    }
+
+    /// Forwards `msg` to the dead letter queue along with why it failed, then removes it from ours.
+    async fn dead_letter(&self, msg: &Message, receipt_handle: &str, reason: &str) -> Result<()> {
+        let Some(dead_letter_queue_url) = &self.opts.dead_letter_queue_url else {
+            return Ok(());
+        };
+        let mut description = reason.to_string();
+        if let Some((idx, _)) = description.char_indices().nth(MAX_ERROR_DESCRIPTION) {
+            description.truncate(idx);
+        }
+        warn!("Dead lettering message {} after {} receives: {}", msg.message_id().unwrap_or_default(), receive_count(msg), description);
+
+        let mut attributes = HashMap::from([
+            (ERROR_DESCRIPTION_ATTRIBUTE.to_string(), string_attribute(description)),
+            (SOURCE_QUEUE_ATTRIBUTE.to_string(), string_attribute(self.queue_url())),
+        ]);
+        // Keep whatever the producer sent along, as far as the attribute limit allows
+        for (name, value) in msg.message_attributes().into_iter().flatten() {
+            if attributes.len() >= MAX_MESSAGE_ATTRIBUTES {
+                break;
+            }
+            attributes.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+
+        self.client
+            .send_message()
+            .queue_url(dead_letter_queue_url)
+            .message_body(msg.body().unwrap_or_default())
+            .set_message_attributes(Some(attributes))
+            .send()
+            .await?;
+        self.client
+            .delete_message()
+            .queue_url(self.queue_url())
+            .receipt_handle(receipt_handle)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Whether `sequencer` is newer than anything committed or in flight for `key`.
+    fn is_newer(&self, key: &str, sequencer: &str) -> bool {
+        self.sequencers.is_newer(key, sequencer)
+            && self
+                .in_flight
+                .values()
+                .filter_map(|msg| msg.sequencers.get(key))
+                .all(|in_flight| compare_sequencers(sequencer, in_flight) == Ordering::Greater)
+    }
+
+    fn parse_message(&self, message_id: &str, body: &str) -> Result<Vec<(FileEvent, Option<String>)>> {
+        Notification::parse(body, self.opts.raw_message_delivery)?
+            .objects()
+            .into_iter()
+            .filter(ObjectNotification::is_object_change)
+            .map(|event| {
+                let sequencer = event.object.sequencer.clone();
+                Ok((self.file_event(message_id, event)?, sequencer))
+            })
+            .collect()
+    }
+
+    fn file_event(&self, message_id: &str, event: ObjectNotification) -> Result<FileEvent> {
+        event.into_file_event(&self.opts.queue_name, AckToken::new(message_id))
     }
 }
 
 impl FileEvents for SqsEvents {
-    async fn next_file(&mut self) -> Result<Vec<Path>> {
-        let msg_que = self.client.receive_message().send().await?;
-        let msgs = msg_que.messages().unwrap_or_default();
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        // Whatever was acked since the last poll is committed, so this is a good point to save
+        self.sequencers.save()?;
+        let msgs = self.receive().await?;
 
-        Ok(msgs
-            .into_iter()
-            .flat_map(|msg| msg.body())
-            .flat_map(|msg| serde_json::from_str::<SqsEvent>(msg))
-            .flat_map(|msg| {
-                msg.records
-                    .into_iter()
-                    .map(|m| {
-                        Path::parse(&format!("{}/{}", m.s3.bucket.name, m.s3.object.key)).unwrap()
-                    })
-                    .collect::<Vec<_>>()
+        // Parse the whole batch first so duplicates and stale events can be spotted across messages
+        let mut received = vec![];
+        for msg in msgs {
+            let (Some(message_id), Some(receipt_handle)) = (msg.message_id(), msg.receipt_handle()) else {
+                continue;
+            };
+            if self.in_flight.contains(message_id) {
+                // Delivered again while its files are still held or being ingested, only the newest
+                // receipt handle can delete it or hand it back, so keep that one instead
+                debug!("Message {} was delivered again while in flight, refreshing its receipt handle", message_id);
+                let heartbeat = self.heartbeat(receipt_handle);
+                let refreshed = msg.clone();
+                if let Some(in_flight) = self.in_flight.get_mut(message_id) {
+                    in_flight.receipt_handle = receipt_handle.to_string();
+                    in_flight.heartbeat = heartbeat;
+                    in_flight.message = refreshed;
+                }
+                continue;
+            }
+            match self.parse_message(message_id, msg.body().unwrap_or_default()) {
+                Ok(events) => received.push((msg, events)),
+                Err(err) => {
+                    // Leave the message alone, it comes back after its visibility timeout until it
+                    // runs out of attempts.
+                    error!("Unable to parse S3 notification in message {}: {:?}", message_id, err);
+                    if self.exhausted(&msg) {
+                        self.dead_letter(&msg, receipt_handle, &format!("{:#}", err)).await?;
+                    }
+                }
+            }
+        }
+        let latest = latest_sequencers(&received);
+
+        let mut files = vec![];
+        let mut emitted = HashSet::new();
+        for (msg, events) in received {
+            let message_id = msg.message_id().unwrap_or_default().to_string();
+            let receipt_handle = msg.receipt_handle().unwrap_or_default().to_string();
+
+            let mut sequencers = HashMap::new();
+            let mut kept = vec![];
+            for (event, sequencer) in events {
+                if let Some(sequencer) = sequencer {
+                    let key = event.path.to_string();
+                    let is_latest = latest
+                        .get(&key)
+                        .map_or(false, |latest| compare_sequencers(&sequencer, latest) == Ordering::Equal);
+                    if !is_latest || !self.is_newer(&key, &sequencer) || !emitted.insert(key.clone()) {
+                        debug!("Dropping stale or duplicate event for {} with sequencer {}", key, sequencer);
+                        continue;
+                    }
+                    sequencers.insert(key, sequencer);
+                }
+                kept.push(event);
+            }
+            if kept.is_empty() {
+                // Test events, notifications we don't ingest and superseded events have nothing to wait on
+                debug!("Message {} contains no object changes to ingest, deleting it", message_id);
+                self.client
+                    .delete_message()
+                    .queue_url(self.queue_url())
+                    .receipt_handle(receipt_handle)
+                    .send()
+                    .await?;
+                continue;
+            }
+
+            let heartbeat = self.heartbeat(&receipt_handle);
+            let files = kept.len();
+            self.in_flight.insert(
+                message_id,
+                InFlightMessage {
+                    message: msg,
+                    receipt_handle,
+                    sequencers,
+                    heartbeat,
+                },
+                files,
+            );
+            files.extend(kept);
+        }
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        let message_id = event.ack_token.as_str();
+        let key = event.path.to_string();
+        if let Some(sequencer) = self.in_flight.get(message_id).and_then(|msg| msg.sequencers.get(&key)) {
+            self.sequencers.record(&key, sequencer);
+        }
+        let Some(InFlightMessage { receipt_handle, heartbeat, .. }) = self.in_flight.ack(message_id) else {
+            return Ok(());
+        };
+        drop(heartbeat);
+        self.client
+            .delete_message()
+            .queue_url(self.queue_url())
+            .receipt_handle(receipt_handle)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        // Once any file in a message fails the whole message goes back on the queue, acks for its
+        // other files will then find nothing in flight and leave it alone.
+        let Some(InFlightMessage { message, receipt_handle, heartbeat, .. }) = self.in_flight.remove(event.ack_token.as_str()) else {
+            return Ok(());
+        };
+        // Stop extending before we hand the message back, or the heartbeat could hide it again
+        drop(heartbeat);
+
+        if self.exhausted(&message) {
+            let reason = format!("Failed to ingest {}: {:#}", event.path, error);
+            return self.dead_letter(&message, &receipt_handle, &reason).await;
+        }
+
+        // Back off rather than handing it straight back, it would most likely fail again right away
+        let timeout = self.opts.retry_visibility_timeout(receive_count(&message));
+        self.client
+            .change_message_visibility()
+            .queue_url(self.queue_url())
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(timeout.as_secs() as i32)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::VecDeque;
+    use std::fs;
+
+    use aws_sdk_sqs::{Config, Credentials, Region};
+
+    use crate::test_utils::{FakeHttpServer, FakeRequest};
+
+    use super::*;
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/landing";
+    const DEAD_LETTER_QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/landing-dead";
+
+    /// A received message holding a single S3 notification for `key`.
+    fn message(id: &str, receive_count: u32, key: &str, sequencer: &str) -> Result<String> {
+        let mut body: serde_json::Value = serde_json::from_str(&fs::read_to_string("./test_files/s3_object_created.json")?)?;
+        body["Records"][0]["s3"]["object"]["key"] = key.into();
+        body["Records"][0]["s3"]["object"]["sequencer"] = sequencer.into();
+        Ok(format!(
+            "<Message><MessageId>{0}</MessageId><ReceiptHandle>receipt-{0}-{2}</ReceiptHandle><Body>{1}</Body>\
+             <Attribute><Name>ApproximateReceiveCount</Name><Value>{2}</Value></Attribute></Message>",
+            id, body, receive_count
+        ))
+    }
+
+    /// Stands in for SQS, each receive gets the next of `receives` and everything else succeeds.
+    async fn fake_sqs(receives: Vec<Vec<String>>) -> Result<FakeHttpServer> {
+        let mut receives = VecDeque::from(receives);
+        FakeHttpServer::start(move |request| {
+            let (action, result) = match action(request).as_str() {
+                "ReceiveMessage" => ("ReceiveMessage", receives.pop_front().unwrap_or_default().concat()),
+                "SendMessage" => ("SendMessage", String::from("<MessageId>dead-1</MessageId>")),
+                _ => return (200, String::new()),
+            };
+            let body = format!(
+                "<{0}Response><{0}Result>{1}</{0}Result><ResponseMetadata><RequestId>1</RequestId></ResponseMetadata></{0}Response>",
+                action, result
+            );
+            (200, body)
+        })
+        .await
+    }
+
+    fn action(request: &FakeRequest) -> String {
+        request.body.split('&').find_map(|param| param.strip_prefix("Action=")).unwrap_or_default().to_string()
+    }
+
+    /// The action of each request, along with the parameter named `with`.
+    fn calls(server: &FakeHttpServer, with: &str) -> Vec<(String, String)> {
+        server
+            .requests()
+            .iter()
+            .map(|request| {
+                let param = request.body.split('&').find_map(|param| param.strip_prefix(&format!("{}=", with))).unwrap_or_default();
+                (action(request), param.to_string())
             })
-            .collect::<Vec<_>>())
+            .collect()
+    }
+
+    async fn events(server: &FakeHttpServer, opts: SqsEventOptions) -> Result<SqsEvents> {
+        let config = Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .endpoint_url(&server.url)
+            .build();
+        let opts = SqsEventOptions {
+            queue_url: Some(QUEUE_URL.to_string()),
+            ..opts
+        };
+        SqsEvents::new(Client::from_conf(config), opts).await
+    }
+
+    #[tokio::test]
+    pub async fn test_ack_and_stale_sequencers() -> Result<()> {
+        let server = fake_sqs(vec![
+            vec![message("1", 1, "a.parquet", "0A")?, message("2", 1, "a.parquet", "09")?, message("3", 1, "b.parquet", "0A")?],
+            vec![message("4", 1, "a.parquet", "08")?],
+        ])
+        .await?;
+        let mut events = events(&server, SqsEventOptions::default()).await?;
+
+        // The older event for a.parquet is superseded within the batch and deleted straight away
+        let files = events.next_file().await?;
+        assert_eq!(files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>(), ["landing-bucket/a.parquet", "landing-bucket/b.parquet"]);
+        assert_eq!(calls(&server, "ReceiptHandle")[1..], [("DeleteMessage".to_string(), "receipt-2-1".to_string())]);
+
+        events.ack(&files[0]).await?;
+        events.ack(&files[0]).await?;
+        assert_eq!(calls(&server, "ReceiptHandle")[2..], [("DeleteMessage".to_string(), "receipt-1-1".to_string())]);
+
+        // Older than what was just committed
+        assert!(events.next_file().await?.is_empty());
+        assert_eq!(calls(&server, "ReceiptHandle")[4..], [("DeleteMessage".to_string(), "receipt-4-1".to_string())]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_redelivered_in_flight() -> Result<()> {
+        let server = fake_sqs(vec![vec![message("1", 1, "a.parquet", "0A")?], vec![message("1", 2, "a.parquet", "0A")?]]).await?;
+        let mut events = events(&server, SqsEventOptions::default()).await?;
+
+        // The redelivery isn't a stale duplicate to delete, it replaces the original receipt handle
+        let files = events.next_file().await?;
+        assert!(events.next_file().await?.is_empty());
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let calls = calls(&server, "ReceiptHandle");
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[2], ("ChangeMessageVisibility".to_string(), "receipt-1-2".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_nack_backs_off() -> Result<()> {
+        let server = fake_sqs(vec![vec![message("1", 3, "a.parquet", "0A")?]]).await?;
+        let opts = SqsEventOptions {
+            retry_backoff: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert_eq!(opts.retry_visibility_timeout(1), Duration::from_secs(10));
+        assert_eq!(opts.retry_visibility_timeout(100), opts.max_retry_backoff);
+        let mut events = events(&server, opts).await?;
+
+        let files = events.next_file().await?;
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        events.ack(&files[0]).await?;
+        let calls = calls(&server, "VisibilityTimeout");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1], ("ChangeMessageVisibility".to_string(), "40".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_dead_letter() -> Result<()> {
+        let server = fake_sqs(vec![vec![message("1", 1, "a.parquet", "0A")?, message("2", 2, "b.parquet", "0A")?]]).await?;
+        let opts = SqsEventOptions {
+            max_receive_count: Some(2),
+            dead_letter_queue_url: Some(DEAD_LETTER_QUEUE_URL.to_string()),
+            ..Default::default()
+        };
+        let mut events = events(&server, opts).await?;
+
+        // Only the message out of receives is dead lettered, the other one is retried
+        let files = events.next_file().await?;
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        events.nack(&files[1], &anyhow!("failed")).await?;
+        let calls = calls(&server, "QueueUrl");
+        let actions = calls.iter().map(|(action, _)| action.as_str()).collect::<Vec<_>>();
+        assert_eq!(actions, ["ReceiveMessage", "ChangeMessageVisibility", "SendMessage", "DeleteMessage"]);
+        assert!(calls[2].1.ends_with("landing-dead"));
+        let sent = &server.requests()[2].body;
+        assert!(sent.contains(ERROR_DESCRIPTION_ATTRIBUTE) && sent.contains(SOURCE_QUEUE_ATTRIBUTE));
+        Ok(())
     }
 }
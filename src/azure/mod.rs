@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+pub mod model;
+pub mod queue;
+
+/// Storage Queues cap a single get at 32 messages.
+pub const MAX_MESSAGES_PER_GET: u8 = 32;
+pub const STORAGE_API_VERSION: &str = "2020-10-02";
+/// Longest a message can be hidden for.
+pub const MAX_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub enum StorageCredentials {
+    /// A SAS token query string, with or without the leading `?`.
+    Sas(String),
+    /// The account name and base64 account key, as shown in the portal.
+    SharedKey { account: String, key: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageQueueEventOptions {
+    /// e.g. `https://account.queue.core.windows.net/landing`, or
+    /// `http://127.0.0.1:10001/devstoreaccount1/landing` for Azurite.
+    pub queue_url: String,
+    pub credentials: StorageCredentials,
+    /// Only blobs in this container are ingested, an Azure `ObjectStore` is bound to one. Events for
+    /// other containers are logged and deleted.
+    pub container: Option<String>,
+    /// Set for emulator urls, which have the account as the first path segment.
+    pub emulator_account: Option<String>,
+    /// Messages per get, between 1 and 32.
+    pub max_messages: u8,
+    /// How long received messages stay hidden from other consumers.
+    pub visibility_timeout: Duration,
+    /// How long a failed message stays hidden before it is retried, doubled on every dequeue.
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+    /// Dequeues after which a failing message is moved to `poison_queue_url`, or dropped when that
+    /// isn't set. Failing messages are retried forever when unset.
+    pub max_dequeue_count: Option<u32>,
+    /// Queue in the same account that failed messages are moved to, e.g. `{queue_url}-poison`.
+    pub poison_queue_url: Option<String>,
+}
+
+impl StorageQueueEventOptions {
+    /// How long a message that failed on its `dequeue_count`th dequeue is hidden for.
+    pub fn retry_visibility_timeout(&self, dequeue_count: u32) -> Duration {
+        let doublings = dequeue_count.saturating_sub(1).min(31);
+        self.retry_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_retry_backoff)
+            .min(MAX_VISIBILITY_TIMEOUT)
+    }
+}
+
+impl Default for StorageQueueEventOptions {
+    fn default() -> Self {
+        Self {
+            queue_url: String::new(),
+            credentials: StorageCredentials::Sas(String::new()),
+            container: None,
+            emulator_account: None,
+            max_messages: MAX_MESSAGES_PER_GET,
+            visibility_timeout: Duration::from_secs(300),
+            retry_backoff: Duration::from_secs(30),
+            max_retry_backoff: Duration::from_secs(15 * 60),
+            max_dequeue_count: None,
+            poison_queue_url: None,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use object_store::path::Path;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+
+use crate::{AckToken, FileEvent, FileEventKind};
+
+pub const BLOB_CREATED: &str = "Microsoft.Storage.BlobCreated";
+pub const BLOB_DELETED: &str = "Microsoft.Storage.BlobDeleted";
+/// Data Lake Gen2 sends a `BlobCreated` when a file is created empty and another once it is
+/// flushed, only the second one means the file is ready.
+pub const CREATE_FILE_API: &str = "CreateFile";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct QueueMessage {
+    pub message_id: String,
+    pub pop_receipt: String,
+    pub dequeue_count: u32,
+    #[serde(default)]
+    pub message_text: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct QueueMessagesList {
+    #[serde(rename = "QueueMessage", default)]
+    pub messages: Vec<QueueMessage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobEventData {
+    pub api: Option<String>,
+    pub e_tag: Option<String>,
+    pub content_length: Option<u64>,
+    pub blob_type: Option<String>,
+    pub url: String,
+    pub sequencer: Option<String>,
+}
+
+/// An event in the Event Grid schema.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EventGridEvent {
+    pub id: String,
+    pub topic: Option<String>,
+    pub subject: String,
+    pub event_type: String,
+    pub event_time: String,
+    pub data: BlobEventData,
+}
+
+/// A blob url split into the parts an Azure `ObjectStore` cares about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobLocation {
+    pub container: String,
+    pub path: Path,
+}
+
+/// Splits `https://account.blob.core.windows.net/container/some/blob` into the container and the
+/// blob's path within it. Emulator urls keep the account in the path, `http://127.0.0.1:10000/devstoreaccount1/container/blob`.
+pub fn parse_blob_url(url: &str, emulator_account: Option<&str>) -> Result<BlobLocation> {
+    let (_, rest) = url.split_once("://").ok_or_else(|| anyhow!("Not a blob url: {}", url))?;
+    let path = rest.split_once('/').map_or("", |(_, path)| path);
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let path = match emulator_account {
+        Some(account) => path.strip_prefix(account).map_or(path, |path| path.trim_start_matches('/')),
+        None => path,
+    };
+    let (container, blob) = path.split_once('/').ok_or_else(|| anyhow!("No blob name in url: {}", url))?;
+    let blob = percent_decode_str(blob).decode_utf8()?;
+    if container.is_empty() || blob.trim_matches('/').is_empty() {
+        return Err(anyhow!("No blob name in url: {}", url));
+    }
+    Ok(BlobLocation {
+        container: container.to_string(),
+        path: Path::parse(blob.trim_matches('/'))?,
+    })
+}
+
+impl EventGridEvent {
+    /// Reads a queue message, which Event Grid base64 encodes by default. A message holds a single
+    /// event, but arrays are accepted too.
+    pub fn parse_message(text: &str) -> Result<Vec<Self>> {
+        let text = text.trim();
+        let json = if text.starts_with('{') || text.starts_with('[') {
+            text.as_bytes().to_vec()
+        } else {
+            STANDARD.decode(text)?
+        };
+        match serde_json::from_slice::<serde_json::Value>(&json)? {
+            value @ serde_json::Value::Array(_) => Ok(serde_json::from_value(value)?),
+            value => Ok(vec![serde_json::from_value(value)?]),
+        }
+    }
+
+    /// Whether a blob was created or deleted, the only events we ingest.
+    pub fn is_object_change(&self) -> bool {
+        match self.event_type.as_str() {
+            BLOB_CREATED => self.data.api.as_deref() != Some(CREATE_FILE_API),
+            BLOB_DELETED => true,
+            _ => false,
+        }
+    }
+
+    /// A file event for the blob's path within its container. Blob ETags aren't content hashes so
+    /// they are left out rather than failing verification.
+    pub fn into_file_event(self, location: BlobLocation, source: &str, ack_token: AckToken) -> FileEvent {
+        let kind = match self.event_type.as_str() {
+            BLOB_DELETED => FileEventKind::Removed,
+            _ => FileEventKind::Created,
+        };
+        FileEvent {
+            kind,
+            event_name: Some(self.event_type),
+            size: self.data.content_length.map(|size| size as usize),
+            event_time: DateTime::parse_from_rfc3339(&self.event_time)
+                .ok()
+                .map(|time| time.with_timezone(&Utc)),
+            ack_token,
+            ..FileEvent::new(location.path, source)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    pub fn test_queue_messages() -> Result<()> {
+        let list: QueueMessagesList = quick_xml::de::from_str(&fs::read_to_string("./test_files/azure_queue_messages.xml")?)?;
+        assert_eq!(list.messages.len(), 2);
+        assert_eq!(list.messages[0].dequeue_count, 1);
+
+        // Base64 encoded, as Event Grid sends by default
+        let events = EventGridEvent::parse_message(&list.messages[0].message_text)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, BLOB_CREATED);
+        assert!(events[0].is_object_change());
+        assert_eq!(events[0].data.content_length, Some(524288));
+
+        // Plain JSON, with the empty file Data Lake creates before writing to it
+        let events = EventGridEvent::parse_message(&list.messages[1].message_text)?;
+        assert_eq!(events[0].data.api.as_deref(), Some(CREATE_FILE_API));
+        assert!(!events[0].is_object_change());
+
+        assert_eq!(QueueMessagesList::default().messages.len(), 0);
+        assert!(EventGridEvent::parse_message("not an event").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_parse_blob_url() -> Result<()> {
+        let location = parse_blob_url("https://acct.blob.core.windows.net/landing/year%3D2023/part%200.parquet", None)?;
+        assert_eq!(location.container, "landing");
+        assert_eq!(location.path.as_ref(), "year=2023/part 0.parquet");
+
+        let location = parse_blob_url("http://127.0.0.1:10000/devstoreaccount1/landing/a.parquet?sv=1", Some("devstoreaccount1"))?;
+        assert_eq!(location.container, "landing");
+        assert_eq!(location.path.as_ref(), "a.parquet");
+
+        assert!(parse_blob_url("https://acct.blob.core.windows.net/landing", None).is_err());
+        assert!(parse_blob_url("landing/a.parquet", None).is_err());
+        Ok(())
+    }
+}
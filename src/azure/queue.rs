@@ -0,0 +1,386 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use reqwest::{Method, Response, Url};
+use sha2::Sha256;
+use tracing::{debug, error, warn};
+
+use crate::in_flight::InFlight;
+use crate::{AckToken, FileEvent, FileEvents};
+
+use super::model::*;
+use super::*;
+
+/// Reads Event Grid blob events from an Azure Storage Queue over the REST API.
+pub struct StorageQueueEvents {
+    client: reqwest::Client,
+    opts: StorageQueueEventOptions,
+    queue_url: Url,
+    poison_queue_url: Option<Url>,
+    // Each received message, for its pop receipt and dequeue count
+    in_flight: InFlight<QueueMessage>,
+}
+
+impl StorageQueueEvents {
+    pub fn new(client: reqwest::Client, opts: StorageQueueEventOptions) -> Result<Self> {
+        if !(1..=MAX_MESSAGES_PER_GET).contains(&opts.max_messages) {
+            return Err(anyhow!("max_messages must be between 1 and {}, got {}", MAX_MESSAGES_PER_GET, opts.max_messages));
+        }
+        let queue_url = Url::parse(opts.queue_url.trim_end_matches('/'))?;
+        let poison_queue_url = opts
+            .poison_queue_url
+            .as_ref()
+            .map(|url| Url::parse(url.trim_end_matches('/')))
+            .transpose()?;
+        Ok(Self {
+            client,
+            opts,
+            queue_url,
+            poison_queue_url,
+            in_flight: InFlight::new(),
+        })
+    }
+
+    fn url(&self, path: &str, query: &[(&str, String)]) -> Url {
+        self.queue_url_for(&self.queue_url, path, query)
+    }
+
+    fn queue_url_for(&self, queue_url: &Url, path: &str, query: &[(&str, String)]) -> Url {
+        let mut url = queue_url.clone();
+        url.set_path(&format!("{}{}", queue_url.path(), path));
+        {
+            let mut pairs = url.query_pairs_mut();
+            for (name, value) in query {
+                pairs.append_pair(name, value);
+            }
+        }
+        if let StorageCredentials::Sas(sas) = &self.opts.credentials {
+            let sas = sas.trim_start_matches('?');
+            let query = match url.query().filter(|query| !query.is_empty()) {
+                Some(query) => format!("{}&{}", query, sas),
+                None => sas.to_string(),
+            };
+            url.set_query(Some(&query));
+        }
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+        url
+    }
+
+    /// Signs a request with the account key, see
+    /// https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key
+    fn shared_key(account: &str, key: &str, method: &Method, url: &Url, headers: &[(&str, String)], content_length: usize) -> Result<String> {
+        let canonical_headers = headers
+            .iter()
+            .filter(|(name, _)| name.starts_with("x-ms-"))
+            .sorted()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .join("");
+        let parameters = url
+            .query_pairs()
+            .into_group_map()
+            .into_iter()
+            .map(|(name, values)| format!("\n{}:{}", name.to_lowercase(), values.iter().sorted().join(",")))
+            .sorted()
+            .join("");
+
+        let content_length = if content_length == 0 { String::new() } else { content_length.to_string() };
+        let string_to_sign = format!("{}\n\n\n{}\n\n\n\n\n\n\n\n\n{}/{}{}{}", method, content_length, canonical_headers, account, url.path(), parameters);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&STANDARD.decode(key)?).map_err(|err| anyhow!("Invalid account key: {}", err))?;
+        mac.update(string_to_sign.as_bytes());
+        Ok(format!("SharedKey {}:{}", account, STANDARD.encode(mac.finalize().into_bytes())))
+    }
+
+    async fn send(&self, method: Method, url: Url, body: Option<String>) -> Result<Response> {
+        let body = body.unwrap_or_default();
+        let headers = [
+            ("x-ms-date", Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+            ("x-ms-version", STORAGE_API_VERSION.to_string()),
+        ];
+        let mut request = self.client.request(method.clone(), url.clone());
+        for (name, value) in &headers {
+            request = request.header(*name, value);
+        }
+        if let StorageCredentials::SharedKey { account, key } = &self.opts.credentials {
+            let authorization = Self::shared_key(account, key, &method, &url, &headers, body.len())?;
+            request = request.header("Authorization", authorization);
+        }
+        Ok(request.body(body).send().await?.error_for_status()?)
+    }
+
+    async fn receive(&self) -> Result<Vec<QueueMessage>> {
+        let url = self.url(
+            "/messages",
+            &[
+                ("numofmessages", self.opts.max_messages.to_string()),
+                ("visibilitytimeout", self.opts.visibility_timeout.as_secs().to_string()),
+            ],
+        );
+        let body = self.send(Method::GET, url, None).await?.text().await?;
+        Ok(quick_xml::de::from_str::<QueueMessagesList>(&body)?.messages)
+    }
+
+    async fn delete(&self, message_id: &str, pop_receipt: &str) -> Result<()> {
+        let url = self.url(&format!("/messages/{}", message_id), &[("popreceipt", pop_receipt.to_string())]);
+        self.send(Method::DELETE, url, None).await?;
+        Ok(())
+    }
+
+    /// Hides the message for `timeout`, after which it is received again.
+    async fn hide(&self, message_id: &str, pop_receipt: &str, timeout: Duration) -> Result<()> {
+        let url = self.url(
+            &format!("/messages/{}", message_id),
+            &[("popreceipt", pop_receipt.to_string()), ("visibilitytimeout", timeout.as_secs().to_string())],
+        );
+        self.send(Method::PUT, url, None).await?;
+        Ok(())
+    }
+
+    /// Moves a message that ran out of dequeues to the poison queue, or drops it if there is none.
+    async fn poison(&self, message: &QueueMessage, reason: &str) -> Result<()> {
+        match &self.poison_queue_url {
+            Some(poison_queue_url) => {
+                warn!("Moving message {} to {} after {} dequeues: {}", message.message_id, poison_queue_url, message.dequeue_count, reason);
+                let url = self.queue_url_for(poison_queue_url, "/messages", &[]);
+                let body = format!("<QueueMessage><MessageText>{}</MessageText></QueueMessage>", quick_xml::escape::escape(&message.message_text));
+                self.send(Method::POST, url, Some(body)).await?;
+            }
+            None => error!("Dropping message {} after {} dequeues: {}", message.message_id, message.dequeue_count, reason),
+        }
+        self.delete(&message.message_id, &message.pop_receipt).await
+    }
+
+    fn parse_message(&self, message: &QueueMessage) -> Result<Vec<FileEvent>> {
+        let mut files = vec![];
+        for event in EventGridEvent::parse_message(&message.message_text)? {
+            if !event.is_object_change() {
+                debug!("Skipping {} event {} for {}", event.event_type, event.id, event.subject);
+                continue;
+            }
+            let location = parse_blob_url(&event.data.url, self.opts.emulator_account.as_deref())?;
+            if let Some(container) = &self.opts.container {
+                if &location.container != container {
+                    warn!("Skipping event {} for {} in container {}, only {} is ingested", event.id, location.path, location.container, container);
+                    continue;
+                }
+            }
+            files.push(event.into_file_event(location, self.queue_url.as_str(), AckToken::new(&message.message_id)));
+        }
+        Ok(files)
+    }
+}
+
+impl FileEvents for StorageQueueEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut files = vec![];
+        for message in self.receive().await? {
+            let events = match self.parse_message(&message) {
+                Ok(events) => events,
+                Err(err) => {
+                    // Leave it alone, it comes back after its visibility timeout
+                    error!("Unable to parse Event Grid message {} (dequeued {} times): {:?}", message.message_id, message.dequeue_count, err);
+                    continue;
+                }
+            };
+            if events.is_empty() {
+                self.delete(&message.message_id, &message.pop_receipt).await?;
+                continue;
+            }
+            self.in_flight.insert(message.message_id.clone(), message, events.len());
+            files.extend(events);
+        }
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        let message_id = event.ack_token.as_str();
+        let Some(message) = self.in_flight.ack(message_id) else {
+            return Ok(());
+        };
+        self.delete(message_id, &message.pop_receipt).await
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        // As with SQS the whole message goes back, acks for its other files then find nothing in flight
+        let Some(message) = self.in_flight.remove(event.ack_token.as_str()) else {
+            return Ok(());
+        };
+        if self.opts.max_dequeue_count.map_or(false, |max| message.dequeue_count >= max) {
+            let reason = format!("Failed to ingest {}: {:#}", event.path, error);
+            return self.poison(&message, &reason).await;
+        }
+
+        // Back off rather than handing it straight back, it would most likely fail again right away
+        let timeout = self.opts.retry_visibility_timeout(message.dequeue_count);
+        self.hide(&message.message_id, &message.pop_receipt, timeout).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::test_utils::FakeHttpServer;
+
+    use super::*;
+
+    /// Azurite's well known development account.
+    const AZURITE_ACCOUNT: &str = "devstoreaccount1";
+    const AZURITE_KEY: &str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+    #[test]
+    pub fn test_shared_key() -> Result<()> {
+        let date = String::from("Mon, 03 Apr 2023 09:30:00 GMT");
+        let headers = [("x-ms-date", date.clone()), ("x-ms-version", STORAGE_API_VERSION.to_string())];
+        let url = Url::parse("http://127.0.0.1:10001/devstoreaccount1/landing/messages?numofmessages=32&visibilitytimeout=300")?;
+        let authorization = StorageQueueEvents::shared_key(AZURITE_ACCOUNT, AZURITE_KEY, &Method::GET, &url, &headers, 0)?;
+        assert_eq!(authorization, "SharedKey devstoreaccount1:yGjwbmVwq1TFjnBrfeEqlIVM72EgCCpKVnmH0AMDer8=");
+
+        // Headers and parameters are sorted, and a body's length is signed
+        let headers = [("x-ms-version", STORAGE_API_VERSION.to_string()), ("x-ms-date", date)];
+        let url = Url::parse("http://127.0.0.1:10001/devstoreaccount1/landing/messages/1?visibilitytimeout=0&popreceipt=abc")?;
+        let authorization = StorageQueueEvents::shared_key(AZURITE_ACCOUNT, AZURITE_KEY, &Method::PUT, &url, &headers, 12)?;
+        assert_eq!(authorization, "SharedKey devstoreaccount1:E4ReQw8TXdRhq2up3JvFZ0mWVntuf/vQDw79voPEXRY=");
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_ack_and_nack() -> Result<()> {
+        let messages = fs::read_to_string("./test_files/azure_queue_messages.xml")?;
+        let mut gets = VecDeque::from([messages.clone(), messages]);
+        let server = FakeHttpServer::start(move |request| {
+            if request.method == "GET" {
+                return (200, gets.pop_front().unwrap_or_else(|| String::from("<QueueMessagesList></QueueMessagesList>")));
+            }
+            (204, String::new())
+        })
+        .await?;
+        let mut events = StorageQueueEvents::new(
+            reqwest::Client::new(),
+            StorageQueueEventOptions {
+                queue_url: format!("{}/landing", server.url),
+                credentials: StorageCredentials::Sas(String::from("?sv=2020-10-02&sig=test")),
+                ..Default::default()
+            },
+        )?;
+        // The method and path of each request so far
+        let calls = |server: &FakeHttpServer| -> Vec<(String, String)> {
+            server
+                .requests()
+                .into_iter()
+                .map(|request| (request.method, request.path.split('?').next().unwrap_or_default().to_string()))
+                .collect()
+        };
+        let created = "/landing/messages/5974b586-0df3-4e2d-ad0c-18e3892bfca2";
+
+        // The empty file Data Lake creates first has nothing to ingest and is deleted straight away
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "year=2023/month=04/part-0003.parquet");
+        assert_eq!(calls(&server)[1], ("DELETE".to_string(), "/landing/messages/0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d".to_string()));
+
+        events.ack(&files[0]).await?;
+        events.ack(&files[0]).await?;
+        assert_eq!(calls(&server).len(), 3);
+        assert_eq!(calls(&server)[2], ("DELETE".to_string(), created.to_string()));
+        assert!(server.requests()[2].path.contains("popreceipt=YzQ4Yzg1MDIGM0MDFiZDAwYzEw"));
+
+        let retried = events.next_file().await?;
+        events.nack(&retried[0], &anyhow!("failed")).await?;
+        events.ack(&retried[0]).await?;
+        let calls = calls(&server);
+        assert_eq!(calls.len(), 6);
+        assert_eq!(calls[5], ("PUT".to_string(), created.to_string()));
+        // Hidden for the retry backoff, not handed straight back
+        assert!(server.requests()[5].path.contains("visibilitytimeout=30"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_poison() -> Result<()> {
+        let messages = fs::read_to_string("./test_files/azure_queue_messages.xml")?;
+        let server = FakeHttpServer::start(move |request| {
+            if request.method == "GET" {
+                return (200, messages.clone());
+            }
+            (204, String::new())
+        })
+        .await?;
+        let mut events = StorageQueueEvents::new(
+            reqwest::Client::new(),
+            StorageQueueEventOptions {
+                queue_url: format!("{}/landing", server.url),
+                credentials: StorageCredentials::Sas(String::from("sv=2020-10-02&sig=test")),
+                max_dequeue_count: Some(1),
+                poison_queue_url: Some(format!("{}/landing-poison", server.url)),
+                ..Default::default()
+            },
+        )?;
+
+        // Out of dequeues, so the nack moves it to the poison queue rather than retrying it
+        let files = events.next_file().await?;
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[2].method, "POST");
+        assert!(requests[2].path.starts_with("/landing-poison/messages?sv=2020-10-02&sig=test"));
+        assert!(requests[2].body.contains("<MessageText>eyJ0b3BpYyI6"));
+        assert_eq!(requests[3].method, "DELETE");
+        assert!(requests[3].path.starts_with("/landing/messages/5974b586-0df3-4e2d-ad0c-18e3892bfca2"));
+        Ok(())
+    }
+
+    /// Runs against Azurite, start it with `azurite-queue`, then run
+    /// `AZURITE_QUEUE_ENDPOINT=http://127.0.0.1:10001/devstoreaccount1 cargo test --features azure test_azurite -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    pub async fn test_azurite() -> Result<()> {
+        let endpoint = std::env::var("AZURITE_QUEUE_ENDPOINT")?;
+        let mut events = StorageQueueEvents::new(
+            reqwest::Client::new(),
+            StorageQueueEventOptions {
+                queue_url: format!("{}/landing-ingest", endpoint),
+                credentials: StorageCredentials::SharedKey {
+                    account: AZURITE_ACCOUNT.to_string(),
+                    key: AZURITE_KEY.to_string(),
+                },
+                container: Some("landing".to_string()),
+                emulator_account: Some(AZURITE_ACCOUNT.to_string()),
+                visibility_timeout: Duration::from_secs(30),
+                // Visible again straight away, so the nacked message comes back within the test
+                retry_backoff: Duration::ZERO,
+                ..Default::default()
+            },
+        )?;
+        events.send(Method::PUT, events.url("", &[]), None).await?;
+
+        let event = serde_json::json!({
+            "id": "1",
+            "subject": "/blobServices/default/containers/landing/blobs/a/b.parquet",
+            "eventType": BLOB_CREATED,
+            "eventTime": "2023-04-03T09:30:00Z",
+            "data": { "api": "PutBlob", "contentLength": 10, "url": format!("{}/landing/a/b.parquet", endpoint.replace("10001", "10000")) },
+        });
+        let body = format!("<QueueMessage><MessageText>{}</MessageText></QueueMessage>", STANDARD.encode(event.to_string()));
+        events.send(Method::POST, events.url("/messages", &[]), Some(body)).await?;
+
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "a/b.parquet");
+        assert_eq!(files[0].size, Some(10));
+
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        assert_eq!(retried[0].path, files[0].path);
+        events.ack(&retried[0]).await?;
+        assert!(events.next_file().await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+use object_store::path::Path;
+
+/// Opaque handle a source hands out with each event so it can find the event again on ack or nack.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct AckToken(pub String);
+
+impl AckToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FileEventKind {
+    #[default]
+    Created,
+    Removed,
+}
+
+/// A file that landed in (or left) storage, along with whatever the source knew about it.
+#[derive(Debug, Clone)]
+pub struct FileEvent {
+    pub path: Path,
+    pub kind: FileEventKind,
+    /// The source's own name for what happened, e.g. `ObjectCreated:Put`.
+    pub event_name: Option<String>,
+    pub size: Option<usize>,
+    pub e_tag: Option<String>,
+    pub version: Option<String>,
+    pub event_time: Option<DateTime<Utc>>,
+    /// Identifies the source that produced this event, e.g. a queue name or watched directory.
+    pub source: String,
+    pub ack_token: AckToken,
+    /// Created files sharing a group are committed to the table in a single transaction, and
+    /// either all acked or all nacked.
+    pub group: Option<String>,
+}
+
+impl FileEvent {
+    pub fn new(path: Path, source: impl Into<String>) -> Self {
+        Self {
+            path,
+            kind: FileEventKind::Created,
+            event_name: None,
+            size: None,
+            e_tag: None,
+            version: None,
+            event_time: None,
+            source: source.into(),
+            ack_token: AckToken::default(),
+            group: None,
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use object_store::path::Path;
+use regex::Regex;
+use tracing::{debug, error};
+
+use crate::{AckToken, FileEvent, FileEventKind, FileEvents};
+
+/// Named group in `key_pattern` whose match becomes the event's path.
+pub const KEY_GROUP: &str = "key";
+
+#[derive(Debug, Clone, Default)]
+pub struct FileFilterOptions {
+    /// Globs a path must match one of, everything passes when empty. `*` stays within a directory,
+    /// `**` crosses them.
+    pub include: Vec<String>,
+    /// Globs a path must match none of.
+    pub exclude: Vec<String>,
+    /// Suffixes a path must end with one of, e.g. `.parquet`, everything passes when empty.
+    pub suffixes: Vec<String>,
+    pub exclude_suffixes: Vec<String>,
+    /// Size bounds for created files, only checked when the source knows the size.
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+    /// Globs an event name must match one of, e.g. `ObjectCreated:*`. Events without a name pass.
+    pub event_names: Vec<String>,
+    /// A path must match this, and if it has a `key` group the path is replaced with what it captured.
+    pub key_pattern: Option<String>,
+}
+
+impl FileFilterOptions {
+    /// Leaves out the markers, checksums and temporary files engines like Spark and Hadoop write
+    /// next to their data files.
+    pub fn data_files() -> Self {
+        Self {
+            exclude: vec![
+                String::from("**/_SUCCESS"),
+                String::from("**/_temporary/**"),
+                String::from("**/.*"),
+            ],
+            exclude_suffixes: vec![String::from(".crc"), String::from(".tmp")],
+            min_size: Some(1),
+            ..Default::default()
+        }
+    }
+}
+
+/// Why an event was filtered out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilterReason {
+    NotIncluded,
+    Excluded,
+    Suffix,
+    Size,
+    EventName,
+    KeyPattern,
+}
+
+pub(crate) fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Wraps a source and drops the events that shouldn't be ingested. Dropped events are acked
+/// straight away, so the source doesn't hand them out again, and counted by reason.
+pub struct FilteredFileEvents<F: FileEvents> {
+    events: F,
+    opts: FileFilterOptions,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    event_names: Option<GlobSet>,
+    key_pattern: Option<Regex>,
+    // Paths rewritten by the key pattern, so acks reach the source with the path it handed out
+    rewritten: HashMap<(AckToken, Path), Path>,
+    passed: u64,
+    filtered: HashMap<FilterReason, u64>,
+}
+
+impl<F: FileEvents> FilteredFileEvents<F> {
+    pub fn new(events: F, opts: FileFilterOptions) -> Result<Self> {
+        Ok(Self {
+            events,
+            include: glob_set(&opts.include)?,
+            exclude: glob_set(&opts.exclude)?,
+            event_names: glob_set(&opts.event_names)?,
+            key_pattern: opts.key_pattern.as_deref().map(Regex::new).transpose()?,
+            opts,
+            rewritten: HashMap::new(),
+            passed: 0,
+            filtered: HashMap::new(),
+        })
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.events
+    }
+
+    /// Events handed on so far.
+    pub fn passed(&self) -> u64 {
+        self.passed
+    }
+
+    /// Events dropped so far, by reason.
+    pub fn filtered(&self) -> &HashMap<FilterReason, u64> {
+        &self.filtered
+    }
+
+    /// Why `event` should be dropped, or the path to ingest it under.
+    fn check(&self, event: &FileEvent) -> std::result::Result<Option<Path>, FilterReason> {
+        let path = event.path.as_ref();
+        if let Some(names) = &self.event_names {
+            if event.event_name.as_deref().map_or(false, |name| !names.is_match(name)) {
+                return Err(FilterReason::EventName);
+            }
+        }
+        if self.include.as_ref().map_or(false, |include| !include.is_match(path)) {
+            return Err(FilterReason::NotIncluded);
+        }
+        if self.exclude.as_ref().map_or(false, |exclude| exclude.is_match(path)) {
+            return Err(FilterReason::Excluded);
+        }
+        let suffix_allowed = self.opts.suffixes.is_empty() || self.opts.suffixes.iter().any(|suffix| path.ends_with(suffix.as_str()));
+        if !suffix_allowed || self.opts.exclude_suffixes.iter().any(|suffix| path.ends_with(suffix.as_str())) {
+            return Err(FilterReason::Suffix);
+        }
+        if let (FileEventKind::Created, Some(size)) = (event.kind, event.size) {
+            if self.opts.min_size.map_or(false, |min| size < min) || self.opts.max_size.map_or(false, |max| size > max) {
+                return Err(FilterReason::Size);
+            }
+        }
+
+        let Some(pattern) = &self.key_pattern else {
+            return Ok(None);
+        };
+        let captures = pattern.captures(path).ok_or(FilterReason::KeyPattern)?;
+        match captures.name(KEY_GROUP) {
+            Some(key) => Path::parse(key.as_str()).map(Some).map_err(|_| FilterReason::KeyPattern),
+            None => Ok(None),
+        }
+    }
+
+    /// The event as the wrapped source handed it out.
+    fn original(&mut self, event: &FileEvent) -> FileEvent {
+        match self.rewritten.remove(&(event.ack_token.clone(), event.path.clone())) {
+            Some(path) => FileEvent { path, ..event.clone() },
+            None => event.clone(),
+        }
+    }
+}
+
+impl<F: FileEvents> FileEvents for FilteredFileEvents<F> {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut files = vec![];
+        for event in self.events.next_file().await? {
+            match self.check(&event) {
+                Ok(key) => {
+                    self.passed += 1;
+                    let Some(key) = key else {
+                        files.push(event);
+                        continue;
+                    };
+                    self.rewritten.insert((event.ack_token.clone(), key.clone()), event.path.clone());
+                    files.push(FileEvent { path: key, ..event });
+                }
+                Err(reason) => {
+                    debug!("Filtered out {} from {}: {:?}", event.path, event.source, reason);
+                    *self.filtered.entry(reason).or_default() += 1;
+                    if let Err(err) = self.events.ack(&event).await {
+                        error!("Unable to ack filtered out {}: {:?}", event.path, err);
+                    }
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        let original = self.original(event);
+        self.events.ack(&original).await
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        let original = self.original(event);
+        self.events.nack(&original, error).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::test_utils::RecordingFileEvents;
+
+    use super::*;
+
+    fn event(path: &str, size: Option<usize>, event_name: Option<&str>) -> FileEvent {
+        FileEvent {
+            size,
+            event_name: event_name.map(String::from),
+            ..FileEvent::new(Path::from(path), "test")
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_data_files() -> Result<()> {
+        let source = RecordingFileEvents::from_events(vec![
+            event("out/year=2023/part-0.parquet", Some(10), None),
+            event("out/_SUCCESS", Some(0), None),
+            event("out/.part-0.parquet.crc", Some(12), None),
+            event("out/part-1.parquet.crc", Some(12), None),
+            event("out/_temporary/0/part-2.parquet", Some(10), None),
+            event("out/part-3.parquet.tmp", Some(10), None),
+            event("out/empty.parquet", Some(0), None),
+            event("out/removed.parquet", None, None),
+        ]);
+        let mut events = FilteredFileEvents::new(source, FileFilterOptions::data_files())?;
+        let files = events.next_file().await?;
+        let paths = files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["out/year=2023/part-0.parquet", "out/removed.parquet"]);
+
+        assert_eq!(events.passed(), 2);
+        assert_eq!(events.filtered()[&FilterReason::Excluded], 3);
+        assert_eq!(events.filtered()[&FilterReason::Suffix], 2);
+        assert_eq!(events.filtered()[&FilterReason::Size], 1);
+        // Filtered events are acked so they aren't retried
+        assert_eq!(events.inner().acked.len(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_key_pattern() -> Result<()> {
+        let source = RecordingFileEvents::from_events(vec![
+            event("landing-bucket/tables/sales/a.parquet", Some(10), Some("ObjectCreated:Put")),
+            event("landing-bucket/tables/sales/b.parquet", Some(10), Some("ObjectCreated:Copy")),
+            event("landing-bucket/tables/sales/c.parquet", Some(10000), Some("ObjectCreated:Put")),
+            event("landing-bucket/other/d.parquet", Some(10), Some("ObjectCreated:Put")),
+            event("landing-bucket/tables/sales/e.csv", Some(10), Some("ObjectCreated:Put")),
+        ]);
+        let mut events = FilteredFileEvents::new(
+            source,
+            FileFilterOptions {
+                include: vec![String::from("landing-bucket/tables/**")],
+                suffixes: vec![String::from(".parquet")],
+                max_size: Some(1000),
+                event_names: vec![String::from("ObjectCreated:Put"), String::from("ObjectCreated:CompleteMultipartUpload")],
+                key_pattern: Some(String::from("^landing-bucket/(?P<key>.*)$")),
+                ..Default::default()
+            },
+        )?;
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "tables/sales/a.parquet");
+        assert_eq!(events.filtered()[&FilterReason::EventName], 1);
+        assert_eq!(events.filtered()[&FilterReason::Size], 1);
+        assert_eq!(events.filtered()[&FilterReason::NotIncluded], 1);
+        assert_eq!(events.filtered()[&FilterReason::Suffix], 1);
+
+        // The source is acked with the path it handed out
+        events.ack(&files[0]).await?;
+        assert_eq!(events.inner().acked.last().map(|path| path.as_ref()), Some("landing-bucket/tables/sales/a.parquet"));
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+pub mod model;
+pub mod pubsub;
+
+pub const PUBSUB_ENDPOINT: &str = "https://pubsub.googleapis.com";
+/// Set by the Pub/Sub emulator's `env-init`, requests to it need no credentials.
+pub const PUBSUB_EMULATOR_HOST: &str = "PUBSUB_EMULATOR_HOST";
+/// Pub/Sub caps a single pull at 1000 messages.
+pub const MAX_MESSAGES_PER_PULL: i32 = 1000;
+/// Longest ack deadline a message can be given.
+pub const MAX_ACK_DEADLINE: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct PubSubEventOptions {
+    pub project: String,
+    pub subscription: String,
+    /// Defaults to the emulator when `PUBSUB_EMULATOR_HOST` is set, and Google's endpoint otherwise.
+    pub endpoint: Option<String>,
+    /// OAuth token to call Pub/Sub with, fetched from the metadata server when unset.
+    pub access_token: Option<String>,
+    /// Messages per pull, between 1 and 1000.
+    pub max_messages: i32,
+    /// How long a pull waits for messages before coming back empty handed.
+    pub wait_time: Duration,
+    /// How long a failed message waits before it is redelivered, doubled on every delivery.
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+    /// Deliveries after which a failing message is acked and dropped. Leave unset when the
+    /// subscription has a dead letter policy, Pub/Sub then moves it to the dead letter topic.
+    pub max_delivery_attempts: Option<u32>,
+}
+
+impl PubSubEventOptions {
+    /// The fully qualified subscription name, `projects/{project}/subscriptions/{subscription}`.
+    pub fn subscription_path(&self) -> String {
+        format!("projects/{}/subscriptions/{}", self.project, self.subscription)
+    }
+
+    /// The ack deadline a message that failed on its `attempt`th delivery is given.
+    pub fn retry_ack_deadline(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.retry_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_retry_backoff)
+            .min(MAX_ACK_DEADLINE)
+    }
+}
+
+impl Default for PubSubEventOptions {
+    fn default() -> Self {
+        Self {
+            project: String::new(),
+            subscription: String::new(),
+            endpoint: None,
+            access_token: None,
+            max_messages: 100,
+            wait_time: Duration::from_secs(20),
+            retry_backoff: Duration::from_secs(10),
+            max_retry_backoff: MAX_ACK_DEADLINE,
+            max_delivery_attempts: None,
+        }
+    }
+}
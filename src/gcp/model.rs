@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use object_store::path::Path;
+use serde::Deserialize;
+
+use crate::{AckToken, FileEvent, FileEventKind};
+
+pub const OBJECT_FINALIZE: &str = "OBJECT_FINALIZE";
+pub const OBJECT_DELETE: &str = "OBJECT_DELETE";
+
+/// The object resource sent as the message data with the `JSON_API_V1` payload format. Numbers
+/// are sent as strings.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GcsObject {
+    pub bucket: String,
+    pub name: String,
+    pub generation: Option<String>,
+    pub size: Option<String>,
+    pub md5_hash: Option<String>,
+    pub etag: Option<String>,
+    pub updated: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PubSubMessage {
+    #[serde(default)]
+    pub data: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    pub message_id: String,
+    pub publish_time: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedMessage {
+    pub ack_id: String,
+    pub message: PubSubMessage,
+    pub delivery_attempt: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PullResponse {
+    #[serde(default)]
+    pub received_messages: Vec<ReceivedMessage>,
+}
+
+/// A GCS notification, read from the message attributes and the object resource if there is one.
+#[derive(Debug, Clone)]
+pub struct GcsNotification {
+    pub event_type: String,
+    pub bucket: String,
+    pub name: String,
+    pub generation: Option<String>,
+    /// Set on the `OBJECT_DELETE` sent when an object is replaced by a newer generation.
+    pub overwritten_by_generation: Option<String>,
+    pub event_time: Option<String>,
+    pub object: Option<GcsObject>,
+}
+
+impl GcsNotification {
+    pub fn parse(message: &PubSubMessage) -> Result<Self> {
+        let attribute = |name: &str| message.attributes.get(name).cloned();
+        let required = |name: &str| attribute(name).ok_or_else(|| anyhow!("Message {} has no {} attribute", message.message_id, name));
+
+        // With the NONE payload format there is no data, everything we need is in the attributes
+        let object = match message.data.as_deref().filter(|data| !data.is_empty()) {
+            Some(data) if attribute("payloadFormat").as_deref() == Some("JSON_API_V1") => {
+                Some(serde_json::from_slice::<GcsObject>(&STANDARD.decode(data)?)?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            event_type: required("eventType")?,
+            bucket: required("bucketId")?,
+            name: required("objectId")?,
+            generation: attribute("objectGeneration"),
+            overwritten_by_generation: attribute("overwrittenByGeneration"),
+            event_time: attribute("eventTime").or_else(|| message.publish_time.clone()),
+            object,
+        })
+    }
+
+    /// Whether this is a new object or a deleted one, the only events we ingest. Deletes caused by an
+    /// overwrite are skipped, the finalize of the new generation covers them.
+    pub fn is_object_change(&self) -> bool {
+        match self.event_type.as_str() {
+            OBJECT_FINALIZE => true,
+            OBJECT_DELETE => self.overwritten_by_generation.is_none(),
+            _ => false,
+        }
+    }
+
+    /// A file event for `bucket/name`. The generation is used as the version, and the base64
+    /// `md5Hash` becomes a hex ETag so it can be verified like an S3 one. Composite objects have no
+    /// MD5 and are not verified.
+    pub fn into_file_event(self, source: &str, ack_token: AckToken) -> Result<FileEvent> {
+        let kind = match self.event_type.as_str() {
+            OBJECT_DELETE => FileEventKind::Removed,
+            _ => FileEventKind::Created,
+        };
+        let path = Path::parse(format!("{}/{}", self.bucket, self.name))?;
+        let object = self.object.as_ref();
+        let e_tag = object
+            .and_then(|object| object.md5_hash.as_deref())
+            .map(|md5| STANDARD.decode(md5).map(hex_encode))
+            .transpose()?;
+        Ok(FileEvent {
+            kind,
+            event_name: Some(self.event_type.clone()),
+            size: object.and_then(|object| object.size.as_deref()).map(str::parse).transpose()?,
+            e_tag,
+            version: self.generation.clone().or_else(|| object.and_then(|object| object.generation.clone())),
+            event_time: self
+                .event_time
+                .as_deref()
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&Utc)),
+            ack_token,
+            ..FileEvent::new(path, source)
+        })
+    }
+}
+
+fn hex_encode(bytes: Vec<u8>) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs;
+
+    use super::*;
+
+    fn fixture(name: &str) -> Result<PullResponse> {
+        Ok(serde_json::from_str(&fs::read_to_string(format!("./test_files/{}", name))?)?)
+    }
+
+    #[test]
+    pub fn test_object_finalize() -> Result<()> {
+        let response = fixture("gcs_object_finalize.json")?;
+        assert_eq!(response.received_messages.len(), 2);
+
+        let received = &response.received_messages[0];
+        let notification = GcsNotification::parse(&received.message)?;
+        assert!(notification.is_object_change());
+        let event = notification.into_file_event("landing", AckToken::new(&received.ack_id))?;
+        assert_eq!(event.path.as_ref(), "landing-bucket/year=2023/month=03/part-0002.parquet");
+        assert_eq!(event.kind, FileEventKind::Created);
+        assert_eq!(event.size, Some(1024));
+        assert_eq!(event.version.as_deref(), Some("1677765600123456"));
+        assert_eq!(event.e_tag.as_deref(), Some("d41d8cd98f00b204e9800998ecf8427e"));
+        assert_eq!(event.ack_token.as_str(), "ack-1");
+
+        // Replaced by a newer generation, the finalize for that one is what we ingest
+        let overwritten = GcsNotification::parse(&response.received_messages[1].message)?;
+        assert_eq!(overwritten.event_type, OBJECT_DELETE);
+        assert!(!overwritten.is_object_change());
+        assert!(overwritten.object.is_none());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_missing_attributes() {
+        let message = PubSubMessage {
+            message_id: "1".to_string(),
+            attributes: HashMap::from([("eventType".to_string(), OBJECT_FINALIZE.to_string())]),
+            ..Default::default()
+        };
+        assert!(GcsNotification::parse(&message).is_err());
+    }
+}
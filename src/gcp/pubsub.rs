@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use crate::in_flight::InFlight;
+use crate::{AckToken, FileEvent, FileEvents};
+
+use super::model::*;
+use super::*;
+
+const METADATA_TOKEN_URL: &str = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+/// Tokens are refreshed this long before they expire.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct MetadataToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+enum Credentials {
+    /// The emulator doesn't check credentials.
+    None,
+    Static(String),
+    Metadata(Option<(String, Instant)>),
+}
+
+/// A message handed out as a file.
+struct Delivery {
+    message_id: String,
+    attempt: u32,
+}
+
+/// Pulls GCS object notifications from a Pub/Sub subscription over the REST API.
+pub struct PubSubEvents {
+    client: reqwest::Client,
+    opts: PubSubEventOptions,
+    endpoint: String,
+    credentials: Credentials,
+    // Each message names a single object, keyed by its ack id
+    in_flight: InFlight<Delivery>,
+    // Deliveries of each message that failed so far. Pub/Sub only counts them itself for
+    // subscriptions with a dead letter policy.
+    failures: HashMap<String, u32>,
+}
+
+impl PubSubEvents {
+    pub fn new(client: reqwest::Client, opts: PubSubEventOptions) -> Result<Self> {
+        if !(1..=MAX_MESSAGES_PER_PULL).contains(&opts.max_messages) {
+            return Err(anyhow!("max_messages must be between 1 and {}, got {}", MAX_MESSAGES_PER_PULL, opts.max_messages));
+        }
+        let emulator = std::env::var(PUBSUB_EMULATOR_HOST).ok().filter(|host| !host.is_empty());
+        let (endpoint, credentials) = match (&opts.endpoint, emulator) {
+            (Some(endpoint), _) => (endpoint.clone(), opts.access_token.clone().map_or(Credentials::None, Credentials::Static)),
+            (None, Some(host)) => (format!("http://{}", host), Credentials::None),
+            (None, None) => (
+                PUBSUB_ENDPOINT.to_string(),
+                opts.access_token.clone().map_or(Credentials::Metadata(None), Credentials::Static),
+            ),
+        };
+
+        Ok(Self {
+            client,
+            opts,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            credentials,
+            in_flight: InFlight::new(),
+            failures: HashMap::new(),
+        })
+    }
+
+    async fn token(&mut self) -> Result<Option<String>> {
+        match &mut self.credentials {
+            Credentials::None => Ok(None),
+            Credentials::Static(token) => Ok(Some(token.clone())),
+            Credentials::Metadata(cached) => {
+                if let Some((token, expires)) = cached {
+                    if Instant::now() + TOKEN_EXPIRY_MARGIN < *expires {
+                        return Ok(Some(token.clone()));
+                    }
+                }
+                let fetched: MetadataToken = self
+                    .client
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let expires = Instant::now() + Duration::from_secs(fetched.expires_in);
+                *cached = Some((fetched.access_token.clone(), expires));
+                Ok(Some(fetched.access_token))
+            }
+        }
+    }
+
+    /// Calls a subscription method such as `pull` or `acknowledge`.
+    async fn call(&mut self, method: &str, body: serde_json::Value, timeout: Duration) -> Result<reqwest::Response> {
+        let url = format!("{}/v1/{}:{}", self.endpoint, self.opts.subscription_path(), method);
+        let mut request = self.client.post(url).json(&body).timeout(timeout);
+        if let Some(token) = self.token().await? {
+            request = request.bearer_auth(token);
+        }
+        Ok(request.send().await?.error_for_status()?)
+    }
+
+    async fn pull(&mut self) -> Result<Vec<ReceivedMessage>> {
+        let body = json!({ "maxMessages": self.opts.max_messages });
+        match self.call("pull", body, self.opts.wait_time).await {
+            Ok(response) => Ok(response.json::<PullResponse>().await?.received_messages),
+            // Pull holds the request open until messages arrive, an empty subscription times out
+            Err(err) if err.downcast_ref::<reqwest::Error>().map_or(false, reqwest::Error::is_timeout) => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn acknowledge(&mut self, ack_ids: Vec<String>) -> Result<()> {
+        if ack_ids.is_empty() {
+            return Ok(());
+        }
+        self.call("acknowledge", json!({ "ackIds": ack_ids }), self.opts.wait_time).await?;
+        Ok(())
+    }
+
+    /// Has the messages redelivered once `deadline` passes instead of their usual ack deadline.
+    async fn redeliver_after(&mut self, ack_ids: Vec<String>, deadline: Duration) -> Result<()> {
+        let body = json!({ "ackIds": ack_ids, "ackDeadlineSeconds": deadline.as_secs() });
+        self.call("modifyAckDeadline", body, self.opts.wait_time).await?;
+        Ok(())
+    }
+}
+
+impl FileEvents for PubSubEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut files = vec![];
+        let mut skipped = vec![];
+        for received in self.pull().await? {
+            let ReceivedMessage { ack_id, message, delivery_attempt } = received;
+            let notification = match GcsNotification::parse(&message) {
+                Ok(notification) => notification,
+                Err(err) => {
+                    // Redelivering won't make it parse, so don't leave it to come back forever
+                    error!("Unable to parse GCS notification in message {}, acking it: {:?}", message.message_id, err);
+                    skipped.push(ack_id);
+                    continue;
+                }
+            };
+            if !notification.is_object_change() {
+                debug!("Message {} is a {} event, acking it", message.message_id, notification.event_type);
+                skipped.push(ack_id);
+                continue;
+            }
+            match notification.into_file_event(&self.opts.subscription, AckToken::new(&ack_id)) {
+                Ok(event) => {
+                    let attempt = delivery_attempt.unwrap_or_else(|| self.failures.get(&message.message_id).map_or(1, |failures| failures + 1));
+                    let delivery = Delivery {
+                        message_id: message.message_id.clone(),
+                        attempt,
+                    };
+                    self.in_flight.insert(ack_id, delivery, 1);
+                    files.push(event);
+                }
+                Err(err) => {
+                    error!("Unable to read GCS notification in message {}, acking it: {:?}", message.message_id, err);
+                    skipped.push(ack_id);
+                }
+            }
+        }
+        self.acknowledge(skipped).await?;
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        let Some(delivery) = self.in_flight.ack(event.ack_token.as_str()) else {
+            return Ok(());
+        };
+        self.failures.remove(&delivery.message_id);
+        self.acknowledge(vec![event.ack_token.as_str().to_string()]).await
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        let Some(delivery) = self.in_flight.remove(event.ack_token.as_str()) else {
+            return Ok(());
+        };
+        if self.opts.max_delivery_attempts.map_or(false, |max| delivery.attempt >= max) {
+            error!("Dropping message {} after {} deliveries, failed to ingest {}: {:#}", delivery.message_id, delivery.attempt, event.path, error);
+            self.failures.remove(&delivery.message_id);
+            return self.acknowledge(vec![event.ack_token.as_str().to_string()]).await;
+        }
+
+        // Back off rather than handing it straight back, it would most likely fail again right away
+        let deadline = self.opts.retry_ack_deadline(delivery.attempt);
+        self.failures.insert(delivery.message_id, delivery.attempt);
+        self.redeliver_after(vec![event.ack_token.as_str().to_string()], deadline).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::VecDeque;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    use crate::test_utils::FakeHttpServer;
+
+    use super::*;
+
+    fn received(ack_id: &str, event_type: Option<&str>) -> serde_json::Value {
+        let attributes = match event_type {
+            Some(event_type) => json!({ "eventType": event_type, "bucketId": "landing-bucket", "objectId": "a/b.parquet" }),
+            None => json!({}),
+        };
+        json!({ "ackId": ack_id, "message": { "messageId": ack_id, "attributes": attributes } })
+    }
+
+    #[tokio::test]
+    pub async fn test_ack_and_nack() -> Result<()> {
+        let mut pulls = VecDeque::from([
+            json!({ "receivedMessages": [
+                received("unparseable", None),
+                received("metadata", Some("OBJECT_METADATA_UPDATE")),
+                received("finalize", Some(OBJECT_FINALIZE)),
+            ]}),
+            json!({ "receivedMessages": [received("redelivered", Some(OBJECT_FINALIZE))] }),
+        ]);
+        let server = FakeHttpServer::start(move |request| {
+            if request.path.ends_with(":pull") {
+                return (200, pulls.pop_front().unwrap_or_else(|| json!({})).to_string());
+            }
+            (200, String::from("{}"))
+        })
+        .await?;
+        let mut events = PubSubEvents::new(
+            reqwest::Client::new(),
+            PubSubEventOptions {
+                project: "test".to_string(),
+                subscription: "landing-ingest".to_string(),
+                endpoint: Some(server.url.clone()),
+                ..Default::default()
+            },
+        )?;
+        // The subscription method called and its body, for each request so far
+        let calls = |server: &FakeHttpServer| -> Vec<(String, serde_json::Value)> {
+            server
+                .requests()
+                .into_iter()
+                .map(|request| (request.path.rsplit(':').next().unwrap_or_default().to_string(), serde_json::from_str(&request.body).unwrap_or_default()))
+                .collect()
+        };
+
+        // Messages we can't parse are acked along with the ones we don't ingest
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "landing-bucket/a/b.parquet");
+        assert_eq!(calls(&server)[1], ("acknowledge".to_string(), json!({ "ackIds": ["unparseable", "metadata"] })));
+
+        events.ack(&files[0]).await?;
+        events.ack(&files[0]).await?;
+        assert_eq!(calls(&server).len(), 3);
+        assert_eq!(calls(&server)[2], ("acknowledge".to_string(), json!({ "ackIds": ["finalize"] })));
+
+        let retried = events.next_file().await?;
+        events.nack(&retried[0], &anyhow!("failed")).await?;
+        events.ack(&retried[0]).await?;
+        let calls = calls(&server);
+        assert_eq!(calls.len(), 5);
+        assert_eq!(calls[4], ("modifyAckDeadline".to_string(), json!({ "ackIds": ["redelivered"], "ackDeadlineSeconds": 10 })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_max_delivery_attempts() -> Result<()> {
+        // The same message delivered twice, with an ack id per delivery
+        let mut redelivered = received("finalize-2", Some(OBJECT_FINALIZE));
+        redelivered["message"]["messageId"] = json!("finalize");
+        let mut pulls = VecDeque::from([
+            json!({ "receivedMessages": [received("finalize", Some(OBJECT_FINALIZE))] }),
+            json!({ "receivedMessages": [redelivered] }),
+        ]);
+        let server = FakeHttpServer::start(move |request| {
+            if request.path.ends_with(":pull") {
+                return (200, pulls.pop_front().unwrap_or_else(|| json!({})).to_string());
+            }
+            (200, String::from("{}"))
+        })
+        .await?;
+        let mut events = PubSubEvents::new(
+            reqwest::Client::new(),
+            PubSubEventOptions {
+                project: "test".to_string(),
+                subscription: "landing-ingest".to_string(),
+                endpoint: Some(server.url.clone()),
+                max_delivery_attempts: Some(2),
+                ..Default::default()
+            },
+        )?;
+
+        let files = events.next_file().await?;
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        events.nack(&retried[0], &anyhow!("failed")).await?;
+        assert!(events.failures.is_empty());
+
+        // Backed off after the first delivery, dropped after the second
+        let bodies = server
+            .requests()
+            .into_iter()
+            .filter(|request| !request.path.ends_with(":pull"))
+            .map(|request| serde_json::from_str(&request.body).unwrap_or_default())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(bodies, vec![json!({ "ackIds": ["finalize"], "ackDeadlineSeconds": 10 }), json!({ "ackIds": ["finalize-2"] })]);
+
+        let opts = PubSubEventOptions::default();
+        assert_eq!(opts.retry_ack_deadline(3), Duration::from_secs(40));
+        assert_eq!(opts.retry_ack_deadline(100), MAX_ACK_DEADLINE);
+        Ok(())
+    }
+
+    /// Runs against the emulator, start it with `gcloud beta emulators pubsub start`, then run
+    /// `PUBSUB_EMULATOR_HOST=localhost:8085 cargo test test_emulator -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    pub async fn test_emulator() -> Result<()> {
+        let host = std::env::var(PUBSUB_EMULATOR_HOST)?;
+        let client = reqwest::Client::new();
+        let base = format!("http://{}/v1/projects/test", host);
+        client.put(format!("{}/topics/landing", base)).send().await?;
+        client
+            .put(format!("{}/subscriptions/landing-ingest", base))
+            .json(&json!({ "topic": "projects/test/topics/landing" }))
+            .send()
+            .await?
+            .error_for_status()?;
+        let object = json!({ "bucket": "landing-bucket", "name": "a/b.parquet", "generation": "7", "size": "10" });
+        let publish = |event_type: &str| {
+            json!({ "messages": [{
+                "data": STANDARD.encode(object.to_string()),
+                "attributes": {
+                    "eventType": event_type,
+                    "bucketId": "landing-bucket",
+                    "objectId": "a/b.parquet",
+                    "objectGeneration": "7",
+                    "payloadFormat": "JSON_API_V1",
+                },
+            }]})
+        };
+        for event_type in ["OBJECT_METADATA_UPDATE", OBJECT_FINALIZE] {
+            client
+                .post(format!("{}/topics/landing:publish", base))
+                .json(&publish(event_type))
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        let mut events = PubSubEvents::new(
+            client,
+            PubSubEventOptions {
+                project: "test".to_string(),
+                subscription: "landing-ingest".to_string(),
+                wait_time: Duration::from_secs(5),
+                // Redelivered straight away, so the nacked message comes back within the test
+                retry_backoff: Duration::ZERO,
+                ..Default::default()
+            },
+        )?;
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "landing-bucket/a/b.parquet");
+        assert_eq!(files[0].version.as_deref(), Some("7"));
+
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        assert_eq!(retried[0].path, files[0].path);
+        events.ack(&retried[0]).await?;
+        assert!(events.next_file().await?.is_empty());
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@ use bytes::{Bytes, BytesMut};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use futures::stream::BoxStream;
 use futures::StreamExt;
-use hdfs::hdfs::{HdfsErr, HdfsFs};
+use hdfs::hdfs::{FileStatus, HdfsErr, HdfsFs};
 use object_store::{GetResult, ListResult, MultipartId, ObjectMeta, ObjectStore};
 use object_store::path::Path;
 use object_store::Result;
@@ -27,6 +27,29 @@ fn map_error(err: HdfsErr) -> object_store::Error {
     }
 }
 
+/// File statuses carry a full url like `hdfs://namenode:9000/some/file`, we only want the path.
+fn status_path(status: &FileStatus) -> Path {
+    let name = status.name();
+    let path = match name.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("", |idx| &rest[idx..]),
+        None => name,
+    };
+    Path::from(path)
+}
+
+fn status_meta(status: &FileStatus) -> ObjectMeta {
+    let dt = NaiveDateTime::from_timestamp_millis(status.last_modified()).unwrap_or(NaiveDateTime::MIN);
+    ObjectMeta {
+        location: status_path(status),
+        last_modified: DateTime::from_utc(dt, Utc),
+        size: status.len(),
+    }
+}
+
+fn prefix_path(prefix: Option<&Path>) -> String {
+    format!("/{}", prefix.map(|p| p.to_string()).unwrap_or_default())
+}
+
 #[derive(Debug)]
 pub struct HdfsObjectStore {
     fs: Arc<HdfsFs>,
@@ -86,20 +109,32 @@ impl ObjectStore for HdfsObjectStore {
     }
 
     async fn list(&self, prefix: Option<&Path>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
-        let path = prefix.cloned().unwrap_or(Path::from("/"));
-        let files: Vec<Result<ObjectMeta>> = self.fs.list_status(path.as_ref()).map_err(map_error)?.into_iter().map(|file_status| {
-            let dt = NaiveDateTime::from_timestamp_millis(file_status.last_modified()).unwrap_or(NaiveDateTime::MIN);
-            Ok(ObjectMeta {
-                location: path.clone(),
-                last_modified: DateTime::from_utc(dt, Utc),
-                size: file_status.len(),
-            })
-        }).collect();
+        // Object store listings are recursive, HDFS only lists one directory at a time
+        let mut files: Vec<Result<ObjectMeta>> = vec![];
+        let mut dirs = vec![prefix_path(prefix)];
+        while let Some(dir) = dirs.pop() {
+            for file_status in self.fs.list_status(&dir).map_err(map_error)? {
+                if file_status.is_directory() {
+                    dirs.push(format!("/{}", status_path(&file_status)));
+                } else {
+                    files.push(Ok(status_meta(&file_status)));
+                }
+            }
+        }
         Ok(tokio_stream::iter(files.into_iter()).boxed())
     }
 
     async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
-        todo!()
+        let mut common_prefixes = vec![];
+        let mut objects = vec![];
+        for file_status in self.fs.list_status(&prefix_path(prefix)).map_err(map_error)? {
+            if file_status.is_directory() {
+                common_prefixes.push(status_path(&file_status));
+            } else {
+                objects.push(status_meta(&file_status));
+            }
+        }
+        Ok(ListResult { common_prefixes, objects })
     }
 
     async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
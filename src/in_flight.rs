@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+/// Messages a queue source has handed files out of, until every one of those files is acked.
+///
+/// Keyed by the ack token every event from a message carries, so acks and nacks find their
+/// message again. `M` is whatever the source needs to settle it, e.g. a receipt handle.
+#[derive(Debug)]
+pub struct InFlight<M> {
+    messages: HashMap<String, (M, usize)>,
+}
+
+impl<M> Default for InFlight<M> {
+    fn default() -> Self {
+        Self {
+            messages: HashMap::new(),
+        }
+    }
+}
+
+impl<M> InFlight<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tracks a message that `files` events were handed out of.
+    pub fn insert(&mut self, token: impl Into<String>, message: M, files: usize) {
+        self.messages.insert(token.into(), (message, files));
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.messages.contains_key(token)
+    }
+
+    pub fn get(&self, token: &str) -> Option<&M> {
+        self.messages.get(token).map(|(message, _)| message)
+    }
+
+    pub fn get_mut(&mut self, token: &str) -> Option<&mut M> {
+        self.messages.get_mut(token).map(|(message, _)| message)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &M> {
+        self.messages.values().map(|(message, _)| message)
+    }
+
+    /// Counts one of the message's files as done, returning the message once all of them are.
+    pub fn ack(&mut self, token: &str) -> Option<M> {
+        let (_, outstanding) = self.messages.get_mut(token)?;
+        *outstanding = outstanding.saturating_sub(1);
+        if *outstanding > 0 {
+            return None;
+        }
+        self.messages.remove(token).map(|(message, _)| message)
+    }
+
+    /// Stops tracking the message, e.g. because one of its files failed and it goes back as a whole.
+    /// Later acks for its other files then find nothing in flight.
+    pub fn remove(&mut self, token: &str) -> Option<M> {
+        self.messages.remove(token).map(|(message, _)| message)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_in_flight() {
+        let mut in_flight = InFlight::new();
+        in_flight.insert("1", "receipt-1", 2);
+        in_flight.insert("2", "receipt-2", 2);
+        assert!(in_flight.contains("1"));
+        assert_eq!(in_flight.get("2"), Some(&"receipt-2"));
+
+        // Only the last file of a message settles it
+        assert_eq!(in_flight.ack("1"), None);
+        assert_eq!(in_flight.ack("1"), Some("receipt-1"));
+        assert!(!in_flight.contains("1"));
+        assert_eq!(in_flight.ack("1"), None);
+
+        // A nacked message is gone, acks for its other files are ignored
+        assert_eq!(in_flight.remove("2"), Some("receipt-2"));
+        assert_eq!(in_flight.ack("2"), None);
+        assert_eq!(in_flight.values().count(), 0);
+    }
+}
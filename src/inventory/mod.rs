@@ -0,0 +1,655 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use deltalake::arrow::array::{Array, Int64Array, StringArray, TimestampMillisecondArray};
+use deltalake::arrow::compute::cast;
+use deltalake::arrow::csv;
+use deltalake::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use deltalake::arrow::record_batch::RecordBatch;
+use deltalake::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use flate2::read::GzDecoder;
+use md5::{Digest, Md5};
+use object_store::path::Path;
+use object_store::{DynObjectStore, ObjectStore};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::aws::model::decode_key;
+use crate::retry::{Attempt, RetryQueue};
+use crate::{state, AckToken, FileEvent, FileEvents};
+
+pub const INVENTORY_SOURCE: &str = "inventory";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InventoryFile {
+    pub key: String,
+    pub size: Option<u64>,
+    #[serde(rename = "MD5checksum")]
+    pub md5_checksum: Option<String>,
+}
+
+/// The `manifest.json` written with every inventory report.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InventoryManifest {
+    pub source_bucket: String,
+    pub creation_timestamp: Option<String>,
+    pub file_format: String,
+    pub file_schema: String,
+    pub files: Vec<InventoryFile>,
+}
+
+/// Column names are `LastModifiedDate` in CSV schemas and `last_modified_date` in Parquet.
+fn normalize(column: &str) -> String {
+    column.trim().replace('_', "").to_lowercase()
+}
+
+/// One object in an inventory report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InventoryRow {
+    pub bucket: String,
+    pub key: String,
+    pub version_id: Option<String>,
+    pub is_latest: Option<bool>,
+    pub is_delete_marker: Option<bool>,
+    pub size: Option<u64>,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub e_tag: Option<String>,
+}
+
+fn string_column(batch: &RecordBatch, columns: &HashMap<String, usize>, name: &str) -> Result<Option<StringArray>> {
+    let Some(idx) = columns.get(name) else {
+        return Ok(None);
+    };
+    let array = cast(batch.column(*idx), &DataType::Utf8)?;
+    Ok(array.as_any().downcast_ref::<StringArray>().cloned())
+}
+
+/// Reads the columns we need out of a batch of either format, CSV columns all come in as strings.
+fn batch_rows(batch: &RecordBatch, url_encoded: bool) -> Result<Vec<InventoryRow>> {
+    let columns = batch
+        .schema()
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(idx, field)| (normalize(field.name()), idx))
+        .collect::<HashMap<_, _>>();
+    let bucket = string_column(batch, &columns, "bucket")?.ok_or_else(|| anyhow!("Inventory has no bucket column"))?;
+    let key = string_column(batch, &columns, "key")?.ok_or_else(|| anyhow!("Inventory has no key column"))?;
+    let version_id = string_column(batch, &columns, "versionid")?;
+    let is_latest = string_column(batch, &columns, "islatest")?;
+    let is_delete_marker = string_column(batch, &columns, "isdeletemarker")?;
+    let e_tag = string_column(batch, &columns, "etag")?;
+    let size = match columns.get("size") {
+        Some(idx) => cast(batch.column(*idx), &DataType::Int64)?.as_any().downcast_ref::<Int64Array>().cloned(),
+        None => None,
+    };
+    let last_modified = match columns.get("lastmodifieddate") {
+        Some(idx) if batch.column(*idx).data_type() == &DataType::Utf8 => None,
+        Some(idx) => cast(batch.column(*idx), &DataType::Timestamp(TimeUnit::Millisecond, None))?
+            .as_any()
+            .downcast_ref::<TimestampMillisecondArray>()
+            .cloned(),
+        None => None,
+    };
+    let last_modified_strings = string_column(batch, &columns, "lastmodifieddate")?;
+
+    let text = |array: &Option<StringArray>, row: usize| {
+        array
+            .as_ref()
+            .filter(|array| !array.is_null(row))
+            .map(|array| array.value(row).to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let flag = |array: &Option<StringArray>, row: usize| text(array, row).map(|value| value.eq_ignore_ascii_case("true"));
+
+    (0..batch.num_rows())
+        .map(|row| {
+            let key = key.value(row);
+            let key = if url_encoded { decode_key(key)? } else { key.to_string() };
+            let modified = match &last_modified {
+                Some(array) if !array.is_null(row) => Utc.timestamp_millis_opt(array.value(row)).single(),
+                Some(_) => None,
+                None => text(&last_modified_strings, row)
+                    .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+                    .map(|time| time.with_timezone(&Utc)),
+            };
+            Ok(InventoryRow {
+                bucket: bucket.value(row).to_string(),
+                key,
+                version_id: text(&version_id, row),
+                is_latest: flag(&is_latest, row),
+                is_delete_marker: flag(&is_delete_marker, row),
+                size: size.as_ref().filter(|size| !size.is_null(row)).map(|size| size.value(row) as u64),
+                last_modified: modified,
+                e_tag: text(&e_tag, row),
+            })
+        })
+        .collect()
+}
+
+/// Reads an ORC file. orc-rust is on a different arrow version than deltalake, so its batches
+/// are passed through IPC rather than used directly.
+#[cfg(feature = "orc")]
+fn read_orc(bytes: Bytes) -> Result<Vec<RecordBatch>> {
+    let batches = orc_rust::ArrowReaderBuilder::try_new(bytes)?.build().collect::<Result<Vec<_>, _>>()?;
+    let Some(first) = batches.first() else {
+        return Ok(vec![]);
+    };
+    let mut ipc = vec![];
+    let mut writer = orc_arrow_ipc::writer::StreamWriter::try_new(&mut ipc, &first.schema())?;
+    for batch in &batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    drop(writer);
+    let reader = deltalake::arrow::ipc::reader::StreamReader::try_new(std::io::Cursor::new(ipc), None)?;
+    Ok(reader.collect::<Result<_, _>>()?)
+}
+
+/// Reads every row of one inventory data file.
+pub fn read_inventory_file(manifest: &InventoryManifest, bytes: Bytes) -> Result<Vec<InventoryRow>> {
+    let batches: Vec<RecordBatch> = match manifest.file_format.to_uppercase().as_str() {
+        "CSV" => {
+            let fields = manifest
+                .file_schema
+                .split(',')
+                .map(|name| Field::new(name.trim(), DataType::Utf8, true))
+                .collect::<Vec<_>>();
+            let mut csv_bytes = vec![];
+            GzDecoder::new(bytes.as_ref()).read_to_end(&mut csv_bytes)?;
+            csv::ReaderBuilder::new()
+                .has_header(false)
+                .with_schema(Arc::new(Schema::new(fields)))
+                .build(std::io::Cursor::new(csv_bytes))?
+                .collect::<Result<_, _>>()?
+        }
+        "PARQUET" => ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()?.collect::<Result<_, _>>()?,
+        #[cfg(feature = "orc")]
+        "ORC" => read_orc(bytes)?,
+        #[cfg(not(feature = "orc"))]
+        "ORC" => return Err(anyhow!("Reading ORC inventories needs the orc feature")),
+        format => return Err(anyhow!("Unknown inventory format {}", format)),
+    };
+    // Only CSV keys are URL encoded
+    let url_encoded = manifest.file_format.eq_ignore_ascii_case("CSV");
+    let mut rows = vec![];
+    for batch in &batches {
+        rows.extend(batch_rows(batch, url_encoded)?);
+    }
+    Ok(rows)
+}
+
+#[derive(Debug, Clone)]
+pub struct InventoryFileEventsOptions {
+    /// The report's `manifest.json`, in the store the inventory is delivered to.
+    pub manifest: Path,
+    /// Only keys starting with this are ingested.
+    pub prefix: Option<String>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    /// Most files handed out per poll.
+    pub batch_size: usize,
+    /// File the backfill progress is kept in, only kept in memory when unset.
+    pub state_file: Option<PathBuf>,
+    /// Progress is saved once it has moved this many rows, after `checkpoint_interval`, and
+    /// whenever a data file is finished. A restart may hand out rows since the last save again.
+    pub checkpoint_rows: usize,
+    pub checkpoint_interval: Duration,
+    /// Times a file is tried before its row is parked in the state file, see
+    /// [InventoryFileEvents::failed].
+    pub max_attempts: u32,
+}
+
+impl Default for InventoryFileEventsOptions {
+    fn default() -> Self {
+        Self {
+            manifest: Path::from("manifest.json"),
+            prefix: None,
+            modified_after: None,
+            modified_before: None,
+            batch_size: 1000,
+            state_file: None,
+            checkpoint_rows: 10_000,
+            checkpoint_interval: Duration::from_secs(30),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// A position in the report, the row of one of the manifest's data files.
+type Position = (usize, usize);
+
+/// A row that ran out of attempts, kept so it can be looked into and ingested by hand.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailedRow {
+    pub path: String,
+    pub row: Position,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct InventoryState {
+    /// The manifest this progress belongs to.
+    manifest: Option<String>,
+    /// Every row before this position is committed, filtered out or failed.
+    committed: Position,
+    #[serde(default)]
+    failed: Vec<FailedRow>,
+}
+
+/// Backfills a bucket from an S3 Inventory report instead of listing it. Rows are handed out in
+/// report order, and the backfill resumes after the last committed row.
+pub struct InventoryFileEvents {
+    storage: Arc<DynObjectStore>,
+    opts: InventoryFileEventsOptions,
+    manifest: InventoryManifest,
+    state: InventoryState,
+    // The next row to hand out, and the rows of the data file it is in
+    cursor: Position,
+    rows: Option<Vec<InventoryRow>>,
+    in_flight: BTreeSet<Position>,
+    queue: RetryQueue,
+    // The progress last written to the state file, and when
+    saved: Position,
+    saved_at: Instant,
+}
+
+fn token(position: Position) -> AckToken {
+    AckToken::new(format!("{}:{}", position.0, position.1))
+}
+
+fn parse_token(token: &AckToken) -> Result<Position> {
+    let (file, row) = token
+        .as_str()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Malformed inventory ack token {}", token.as_str()))?;
+    Ok((file.parse()?, row.parse()?))
+}
+
+impl InventoryFileEvents {
+    pub async fn new(storage: Arc<DynObjectStore>, opts: InventoryFileEventsOptions) -> Result<Self> {
+        let manifest: InventoryManifest = serde_json::from_slice(&storage.get(&opts.manifest).await?.bytes().await?)?;
+        let mut state: InventoryState = match &opts.state_file {
+            Some(location) => state::load(location)?,
+            None => InventoryState::default(),
+        };
+        let manifest_key = format!("{}@{}", opts.manifest, manifest.creation_timestamp.as_deref().unwrap_or_default());
+        if state.manifest.as_ref() != Some(&manifest_key) {
+            if state.manifest.is_some() {
+                warn!("Saved progress is for {:?}, starting {} from the beginning", state.manifest, manifest_key);
+            }
+            // Rows that failed in an earlier report are kept until someone looks into them
+            state = InventoryState {
+                manifest: Some(manifest_key),
+                committed: (0, 0),
+                failed: state.failed,
+            };
+        }
+
+        Ok(Self {
+            storage,
+            cursor: state.committed,
+            saved: state.committed,
+            saved_at: Instant::now(),
+            queue: RetryQueue::new(opts.max_attempts),
+            opts,
+            manifest,
+            state,
+            rows: None,
+            in_flight: BTreeSet::new(),
+        })
+    }
+
+    /// Rows that ran out of attempts, in this report or an earlier one.
+    pub fn failed(&self) -> &[FailedRow] {
+        &self.state.failed
+    }
+
+    /// Whether every row of the report has been committed or filtered out.
+    pub fn is_done(&self) -> bool {
+        self.cursor.0 >= self.manifest.files.len() && self.in_flight.is_empty()
+    }
+
+    fn matches(&self, row: &InventoryRow) -> bool {
+        // Only the current version of objects that still exist
+        if row.is_delete_marker == Some(true) || row.is_latest == Some(false) {
+            return false;
+        }
+        if let Some(prefix) = &self.opts.prefix {
+            if !row.key.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        let modified = row.last_modified;
+        let after = self.opts.modified_after.map_or(true, |after| modified.map_or(false, |m| m > after));
+        let before = self.opts.modified_before.map_or(true, |before| modified.map_or(false, |m| m < before));
+        after && before
+    }
+
+    async fn load(&self, idx: usize) -> Result<Vec<InventoryRow>> {
+        let file = &self.manifest.files[idx];
+        let bytes = self.storage.get(&Path::from(file.key.as_str())).await?.bytes().await?;
+        if let Some(checksum) = &file.md5_checksum {
+            let digest = format!("{:x}", Md5::digest(&bytes));
+            if !digest.eq_ignore_ascii_case(checksum) {
+                return Err(anyhow!("Inventory file {} has MD5 {} but the manifest says {}", file.key, digest, checksum));
+            }
+        }
+        debug!("Reading inventory file {} of {}: {}", idx + 1, self.manifest.files.len(), file.key);
+        read_inventory_file(&self.manifest, bytes)
+    }
+
+    fn file_event(&self, position: Position, row: &InventoryRow) -> Result<FileEvent> {
+        Ok(FileEvent {
+            event_name: Some(String::from("Inventory")),
+            size: row.size.map(|size| size as usize),
+            e_tag: row.e_tag.clone(),
+            version: row.version_id.clone(),
+            event_time: row.last_modified,
+            ack_token: token(position),
+            ..FileEvent::new(Path::parse(format!("{}/{}", row.bucket, row.key))?, INVENTORY_SOURCE)
+        })
+    }
+
+    /// Writes the progress to the state file now, e.g. before shutting down.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if let Some(location) = &self.opts.state_file {
+            state::store(location, &self.state)?;
+        }
+        self.saved = self.state.committed;
+        self.saved_at = Instant::now();
+        Ok(())
+    }
+
+    /// Moves the progress up to the first row still in flight, saving it when a checkpoint is due.
+    fn advance(&mut self) -> Result<()> {
+        let committed = self.in_flight.iter().next().copied().unwrap_or(self.cursor);
+        self.state.committed = committed;
+        if committed == self.saved {
+            return Ok(());
+        }
+        let finished_file = committed.0 > self.saved.0;
+        let rows = committed.1.saturating_sub(self.saved.1);
+        if finished_file || rows >= self.opts.checkpoint_rows || self.saved_at.elapsed() >= self.opts.checkpoint_interval {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn settle(&mut self, event: &FileEvent) -> Result<()> {
+        self.in_flight.remove(&parse_token(&event.ack_token)?);
+        self.advance()
+    }
+}
+
+impl FileEvents for InventoryFileEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut handed_out = 0;
+        while handed_out < self.opts.batch_size && self.cursor.0 < self.manifest.files.len() {
+            let rows = match self.rows.take() {
+                Some(rows) => rows,
+                None => self.load(self.cursor.0).await?,
+            };
+            while handed_out < self.opts.batch_size && self.cursor.1 < rows.len() {
+                let row = &rows[self.cursor.1];
+                if self.matches(row) {
+                    let event = self.file_event(self.cursor, row)?;
+                    self.queue.push(event);
+                    self.in_flight.insert(self.cursor);
+                    handed_out += 1;
+                }
+                self.cursor.1 += 1;
+            }
+            if self.cursor.1 < rows.len() {
+                self.rows = Some(rows);
+            } else {
+                self.cursor = (self.cursor.0 + 1, 0);
+            }
+        }
+        // Rows filtered out count as done too
+        self.advance()?;
+        Ok(self.queue.take())
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        self.queue.ack(event);
+        self.settle(event)
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        let Attempt::Exhausted(attempts) = self.queue.nack(event) else {
+            return Ok(());
+        };
+        warn!("{} failed {} times, parking it in the state file: {:#}", event.path, attempts, error);
+        self.state.failed.push(FailedRow {
+            path: event.path.to_string(),
+            row: parse_token(&event.ack_token)?,
+            error: format!("{:#}", error),
+        });
+        self.in_flight.remove(&parse_token(&event.ack_token)?);
+        self.advance()?;
+        self.checkpoint()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    const SCHEMA: &str = "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, LastModifiedDate, ETag";
+    const ROWS: &str = r#""landing","tables/a%3D1/1.parquet","v1","true","false","10","2023-01-01T00:00:00.000Z","e1"
+"landing","tables/a%3D1/2.parquet","v2","false","false","10","2023-01-02T00:00:00.000Z","e2"
+"landing","tables/a%3D1/3.parquet","v3","true","true","","2023-01-03T00:00:00.000Z",""
+"landing","other/4.parquet","v4","true","false","10","2023-01-04T00:00:00.000Z","e4"
+"landing","tables/a%3D2/5+file.parquet","v5","true","false","10","2023-01-05T00:00:00.000Z","e5"
+"landing","tables/a%3D2/6.parquet","v6","true","false","10","2023-01-06T00:00:00.000Z","e6"
+"landing","tables/a%3D3/7.parquet","v7","true","false","10","2022-12-31T00:00:00.000Z","e7"
+"#;
+
+    async fn inventory() -> Result<Arc<DynObjectStore>> {
+        let storage = Arc::new(InMemory::new());
+        let (first, second) = ROWS.split_at(ROWS.find("\"landing\",\"tables/a%3D2/5").unwrap());
+        let mut files = vec![];
+        for (idx, rows) in [first, second].into_iter().enumerate() {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(rows.as_bytes())?;
+            let bytes = encoder.finish()?;
+            let key = format!("inventory/landing/data/{}.csv.gz", idx);
+            files.push(serde_json::json!({ "key": key, "size": bytes.len(), "MD5checksum": format!("{:x}", Md5::digest(&bytes)) }));
+            storage.put(&Path::from(key), bytes.into()).await?;
+        }
+        let manifest = serde_json::json!({
+            "sourceBucket": "landing",
+            "destinationBucket": "arn:aws:s3:::inventory",
+            "version": "2016-11-30",
+            "creationTimestamp": "1672876800000",
+            "fileFormat": "CSV",
+            "fileSchema": SCHEMA,
+            "files": files,
+        });
+        storage.put(&Path::from("inventory/landing/manifest.json"), manifest.to_string().into()).await?;
+        Ok(storage)
+    }
+
+    fn options(state_file: Option<PathBuf>) -> InventoryFileEventsOptions {
+        InventoryFileEventsOptions {
+            manifest: Path::from("inventory/landing/manifest.json"),
+            prefix: Some("tables/".to_string()),
+            modified_after: Some(Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()),
+            batch_size: 2,
+            state_file,
+            checkpoint_rows: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_inventory() -> Result<()> {
+        let storage = inventory().await?;
+        let state_file = tempfile::tempdir()?.into_path().join("inventory.json");
+        let mut events = InventoryFileEvents::new(storage.clone(), options(Some(state_file.clone()))).await?;
+
+        // Old versions, delete markers, other prefixes and old objects are left out
+        let first = events.next_file().await?;
+        let paths = first.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["landing/tables/a=2/5 file.parquet", "landing/tables/a=2/6.parquet"]);
+        assert_eq!(first[0].version.as_deref(), Some("v5"));
+        assert_eq!(first[0].size, Some(10));
+        events.ack(&first[0]).await?;
+        assert!(!events.is_done());
+
+        // An interrupted backfill picks up from the first row that wasn't committed
+        let mut events = InventoryFileEvents::new(storage, options(Some(state_file))).await?;
+        let resumed = events.next_file().await?;
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].path.as_ref(), "landing/tables/a=2/6.parquet");
+        events.ack(&resumed[0]).await?;
+        assert!(events.next_file().await?.is_empty());
+        assert!(events.is_done());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_checkpoints() -> Result<()> {
+        let storage = inventory().await?;
+        let state_file = tempfile::tempdir()?.into_path().join("inventory.json");
+        let opts = InventoryFileEventsOptions {
+            batch_size: 1,
+            checkpoint_rows: 10,
+            ..options(Some(state_file.clone()))
+        };
+        let saved = || -> Result<Position> { Ok(state::load::<InventoryState>(&state_file)?.committed) };
+        let mut events = InventoryFileEvents::new(storage, opts).await?;
+
+        // Finishing the first data file is saved straight away
+        let first = events.next_file().await?;
+        assert_eq!(saved()?, (1, 0));
+
+        // Acks within a data file wait for the next checkpoint
+        events.ack(&first[0]).await?;
+        assert_eq!(saved()?, (1, 0));
+        events.checkpoint()?;
+        assert_eq!(saved()?, (1, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_failed_rows() -> Result<()> {
+        let storage = inventory().await?;
+        let state_file = tempfile::tempdir()?.into_path().join("inventory.json");
+        let opts = InventoryFileEventsOptions {
+            max_attempts: 2,
+            ..options(Some(state_file.clone()))
+        };
+        let mut events = InventoryFileEvents::new(storage.clone(), opts.clone()).await?;
+        let files = events.next_file().await?;
+        events.ack(&files[1]).await?;
+
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        assert_eq!(retried[0].path, files[0].path);
+        events.nack(&retried[0], &anyhow!("failed")).await?;
+        assert!(events.is_done());
+
+        // Out of attempts, the row is parked rather than forgotten
+        let events = InventoryFileEvents::new(storage, opts).await?;
+        assert_eq!(events.failed().len(), 1);
+        assert_eq!(events.failed()[0].path, "landing/tables/a=2/5 file.parquet");
+        assert_eq!(events.failed()[0].row, (1, 0));
+        Ok(())
+    }
+
+    fn manifest(file_format: &str) -> InventoryManifest {
+        InventoryManifest {
+            source_bucket: "landing".to_string(),
+            creation_timestamp: None,
+            file_format: file_format.to_string(),
+            file_schema: String::new(),
+            files: vec![],
+        }
+    }
+
+    #[test]
+    pub fn test_unknown_format() {
+        assert!(read_inventory_file(&manifest("JSON"), Bytes::new()).is_err());
+    }
+
+    /// Rows as S3 writes them to Parquet and ORC inventories of a versioned bucket.
+    fn columnar_batch() -> Result<RecordBatch> {
+        let schema = Schema::new(vec![
+            Field::new("bucket", DataType::Utf8, false),
+            Field::new("key", DataType::Utf8, false),
+            Field::new("version_id", DataType::Utf8, true),
+            Field::new("is_latest", DataType::Utf8, true),
+            Field::new("size", DataType::Int64, true),
+        ]);
+        Ok(RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(StringArray::from(vec!["landing", "landing"])),
+                // Unlike CSV, keys in columnar inventories aren't URL encoded
+                Arc::new(StringArray::from(vec!["tables/a=1/1+1.parquet", "tables/a=1/2.parquet"])),
+                Arc::new(StringArray::from(vec![Some("v1"), None])),
+                Arc::new(StringArray::from(vec!["true", "false"])),
+                Arc::new(Int64Array::from(vec![Some(10), None])),
+            ],
+        )?)
+    }
+
+    fn check_columnar(rows: Vec<InventoryRow>) {
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].key, "tables/a=1/1+1.parquet");
+        assert_eq!(rows[0].version_id.as_deref(), Some("v1"));
+        assert_eq!(rows[0].is_latest, Some(true));
+        assert_eq!(rows[0].size, Some(10));
+        assert_eq!(rows[1].is_latest, Some(false));
+        assert_eq!(rows[1].size, None);
+    }
+
+    #[test]
+    pub fn test_parquet_inventory() -> Result<()> {
+        let batch = columnar_batch()?;
+        let mut bytes = vec![];
+        let mut writer = deltalake::parquet::arrow::ArrowWriter::try_new(&mut bytes, batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        check_columnar(read_inventory_file(&manifest("Parquet"), bytes.into())?);
+        Ok(())
+    }
+
+    #[cfg(feature = "orc")]
+    #[test]
+    pub fn test_orc_inventory() -> Result<()> {
+        // The writer takes batches of orc-rust's arrow, so this one crosses over as IPC too
+        let batch = columnar_batch()?;
+        let mut ipc = vec![];
+        let mut ipc_writer = deltalake::arrow::ipc::writer::StreamWriter::try_new(&mut ipc, &batch.schema())?;
+        ipc_writer.write(&batch)?;
+        ipc_writer.finish()?;
+        drop(ipc_writer);
+        let mut reader = orc_arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(ipc), None)?;
+        let batch = reader.next().ok_or_else(|| anyhow!("No batch"))??;
+
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = orc_rust::ArrowWriterBuilder::new(file.reopen()?, batch.schema()).try_build()?;
+        writer.write(&batch)?;
+        writer.close()?;
+        let bytes = std::fs::read(file.path())?;
+        check_columnar(read_inventory_file(&manifest("ORC"), bytes.into())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+use tokio::time::{timeout, Instant};
+use tracing::{error, warn};
+
+use crate::payload::{parse_payload, PayloadFormat};
+use crate::retry::{Attempt, RetryQueue};
+use crate::{AckToken, FileEvent, FileEvents};
+
+#[derive(Debug, Clone)]
+pub struct KafkaFileEventsOptions {
+    pub brokers: String,
+    pub group_id: String,
+    pub topics: Vec<String>,
+    pub payload: PayloadFormat,
+    /// Most messages read per poll.
+    pub max_messages: usize,
+    /// How long a poll waits for the first message.
+    pub poll_timeout: Duration,
+    /// Times a file is tried before it goes to the dead letter topic.
+    pub max_attempts: u32,
+    /// Topic a file is written to once it is out of attempts, as `{"path", "error", "topic",
+    /// "partition", "offset"}`. Without one its partition is paused and left uncommitted, so
+    /// nothing is lost but the partition stops until the consumer restarts.
+    pub dead_letter_topic: Option<String>,
+    /// Passed straight through to librdkafka, e.g. security settings.
+    pub config: HashMap<String, String>,
+}
+
+impl Default for KafkaFileEventsOptions {
+    fn default() -> Self {
+        Self {
+            brokers: String::from("localhost:9092"),
+            group_id: String::from("delta-file-ingest"),
+            topics: vec![],
+            payload: PayloadFormat::default(),
+            max_messages: 100,
+            poll_timeout: Duration::from_secs(10),
+            max_attempts: 5,
+            dead_letter_topic: None,
+            config: HashMap::new(),
+        }
+    }
+}
+
+/// Offsets read from one partition that still have files waiting on a commit.
+#[derive(Debug, Default)]
+struct PartitionOffsets {
+    outstanding: BTreeMap<i64, usize>,
+    next: i64,
+}
+
+impl PartitionOffsets {
+    /// The offset to commit, everything before it is done.
+    fn committable(&self) -> i64 {
+        self.outstanding.keys().next().copied().unwrap_or(self.next)
+    }
+}
+
+/// Reads "file landed" messages from Kafka topics. Offsets are only committed once every file in
+/// a message, and every message before it in the partition, has been committed to the table.
+pub struct KafkaFileEvents {
+    consumer: StreamConsumer,
+    opts: KafkaFileEventsOptions,
+    partitions: HashMap<(String, i32), PartitionOffsets>,
+    queue: RetryQueue,
+    dead_letters: Option<FutureProducer>,
+    paused: BTreeSet<(String, i32)>,
+}
+
+fn ack_token(topic: &str, partition: i32, offset: i64) -> AckToken {
+    AckToken::new(format!("{}:{}:{}", topic, partition, offset))
+}
+
+fn parse_ack_token(token: &AckToken) -> Result<(String, i32, i64)> {
+    let mut parts = token.as_str().rsplitn(3, ':');
+    let (Some(offset), Some(partition), Some(topic)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(anyhow!("Malformed Kafka ack token {}", token.as_str()));
+    };
+    Ok((topic.to_string(), partition.parse()?, offset.parse()?))
+}
+
+impl KafkaFileEvents {
+    pub fn new(opts: KafkaFileEventsOptions) -> Result<Self> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &opts.brokers)
+            .set("group.id", &opts.group_id)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest");
+        for (key, value) in &opts.config {
+            config.set(key, value);
+        }
+        let consumer: StreamConsumer = config.create()?;
+        let topics = opts.topics.iter().map(String::as_str).collect::<Vec<_>>();
+        consumer.subscribe(&topics)?;
+
+        let dead_letters = match &opts.dead_letter_topic {
+            Some(_) => {
+                let mut config = ClientConfig::new();
+                config.set("bootstrap.servers", &opts.brokers);
+                for (key, value) in &opts.config {
+                    config.set(key, value);
+                }
+                Some(config.create()?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            consumer,
+            queue: RetryQueue::new(opts.max_attempts),
+            opts,
+            partitions: HashMap::new(),
+            dead_letters,
+            paused: BTreeSet::new(),
+        })
+    }
+
+    /// Partitions stopped because a file in them ran out of attempts with no dead letter topic.
+    pub fn paused(&self) -> &BTreeSet<(String, i32)> {
+        &self.paused
+    }
+
+    fn source(&self) -> String {
+        self.opts.topics.join(",")
+    }
+
+    /// Marks one file of the message behind `token` as done, committing the partition's offset
+    /// if that finished the oldest outstanding message.
+    fn complete(&mut self, token: &AckToken) -> Result<()> {
+        let (topic, partition, offset) = parse_ack_token(token)?;
+        let Some(offsets) = self.partitions.get_mut(&(topic.clone(), partition)) else {
+            return Ok(());
+        };
+        let before = offsets.committable();
+        if let Some(outstanding) = offsets.outstanding.get_mut(&offset) {
+            *outstanding = outstanding.saturating_sub(1);
+            if *outstanding == 0 {
+                offsets.outstanding.remove(&offset);
+            }
+        }
+        let after = offsets.committable();
+        self.commit(&topic, partition, before, after)
+    }
+
+    /// Records a message read from a partition, committing straight away if it has no files and
+    /// nothing before it is outstanding.
+    fn track(&mut self, topic: &str, partition: i32, offset: i64, files: usize) -> Result<()> {
+        let offsets = self.partitions.entry((topic.to_string(), partition)).or_default();
+        let before = offsets.committable();
+        offsets.next = offsets.next.max(offset + 1);
+        if files > 0 {
+            offsets.outstanding.insert(offset, files);
+        }
+        let after = offsets.committable();
+        self.commit(topic, partition, before, after)
+    }
+
+    /// Commits the partition's offset if it moved on from `before` to `after`.
+    fn commit(&self, topic: &str, partition: i32, before: i64, after: i64) -> Result<()> {
+        if after <= before {
+            return Ok(());
+        }
+        let mut tpl = TopicPartitionList::new();
+        tpl.add_partition_offset(topic, partition, Offset::Offset(after))?;
+        self.consumer.commit(&tpl, CommitMode::Async)?;
+        Ok(())
+    }
+}
+
+impl FileEvents for KafkaFileEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut received = 0;
+        let deadline = Instant::now() + self.opts.poll_timeout;
+        while received < self.opts.max_messages {
+            // Wait for the first message, after that only take what has already arrived
+            let wait = if received == 0 && self.queue.is_empty() {
+                deadline.saturating_duration_since(Instant::now())
+            } else {
+                Duration::ZERO
+            };
+            let (topic, partition, offset, events) = {
+                let Ok(msg) = timeout(wait, self.consumer.recv()).await else {
+                    break;
+                };
+                let msg = msg?;
+                let (topic, partition, offset) = (msg.topic().to_string(), msg.partition(), msg.offset());
+                let token = ack_token(&topic, partition, offset);
+                let event_time = msg.timestamp().to_millis().and_then(|ms| Utc.timestamp_millis_opt(ms).single());
+                let events = match parse_payload(&self.opts.payload, msg.payload().unwrap_or_default(), &self.source(), &token) {
+                    Ok(events) => events,
+                    Err(err) => {
+                        // Nothing will ever make this message parse, so don't let it hold up the partition
+                        error!("Skipping unparseable message at {}: {:?}", token.as_str(), err);
+                        vec![]
+                    }
+                };
+                let events = events.into_iter().map(|event| FileEvent {
+                    event_time: event.event_time.or(event_time),
+                    ..event
+                });
+                (topic, partition, offset, events.collect::<Vec<_>>())
+            };
+            self.track(&topic, partition, offset, events.len())?;
+            self.queue.extend(events);
+            received += 1;
+        }
+        Ok(self.queue.take())
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        self.queue.ack(event);
+        self.complete(&event.ack_token)
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        // Kafka can't redeliver a single message, so retry it ourselves and keep its offset uncommitted
+        let Attempt::Exhausted(attempts) = self.queue.nack(event) else {
+            return Ok(());
+        };
+        let (topic, partition, offset) = parse_ack_token(&event.ack_token)?;
+        let (Some(producer), Some(dead_letter_topic)) = (&self.dead_letters, &self.opts.dead_letter_topic) else {
+            error!("{} from {} failed {} times, pausing the partition: {:#}", event.path, event.ack_token.as_str(), attempts, error);
+            let mut tpl = TopicPartitionList::new();
+            tpl.add_partition(&topic, partition);
+            self.consumer.pause(&tpl)?;
+            self.paused.insert((topic, partition));
+            return Ok(());
+        };
+
+        warn!("{} from {} failed {} times, sending it to {}: {:#}", event.path, event.ack_token.as_str(), attempts, dead_letter_topic, error);
+        let payload = serde_json::json!({
+            "path": event.path.as_ref(),
+            "error": format!("{:#}", error),
+            "topic": topic,
+            "partition": partition,
+            "offset": offset,
+        })
+        .to_string();
+        let record = FutureRecord::to(dead_letter_topic).key(event.ack_token.as_str()).payload(&payload);
+        producer.send(record, self.opts.poll_timeout).await.map_err(|(err, _)| err)?;
+        self.complete(&event.ack_token)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use rdkafka::mocking::MockCluster;
+
+    use super::*;
+
+    #[test]
+    pub fn test_ack_token() -> Result<()> {
+        let token = ack_token("file:events", 3, 42);
+        assert_eq!(parse_ack_token(&token)?, ("file:events".to_string(), 3, 42));
+        assert!(parse_ack_token(&AckToken::new("nope")).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_commits_after_ack() -> Result<()> {
+        let cluster = MockCluster::new(1)?;
+        cluster.create_topic("landed", 1, 1)?;
+        produce(&cluster, "landed", &["bucket/a.parquet", "bucket/b.parquet"]).await?;
+
+        let mut events = KafkaFileEvents::new(KafkaFileEventsOptions {
+            brokers: cluster.bootstrap_servers(),
+            topics: vec!["landed".to_string()],
+            payload: PayloadFormat::Path,
+            ..Default::default()
+        })?;
+        let mut files = vec![];
+        while files.len() < 2 {
+            files.extend(events.next_file().await?);
+        }
+        assert_eq!(files[0].path.as_ref(), "bucket/a.parquet");
+
+        let committed = |events: &KafkaFileEvents| -> Result<Offset> {
+            let tpl = events.consumer.committed(Duration::from_secs(5))?;
+            Ok(tpl.find_partition("landed", 0).map(|p| p.offset()).unwrap_or(Offset::Invalid))
+        };
+
+        // The second message can't be committed while the first is outstanding
+        events.ack(&files[1]).await?;
+        assert_eq!(events.partitions[&("landed".to_string(), 0)].committable(), 0);
+
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        assert_eq!(retried[0].path, files[0].path);
+        events.ack(&retried[0]).await?;
+        assert_eq!(events.partitions[&("landed".to_string(), 0)].committable(), 2);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(committed(&events)?, Offset::Offset(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_commits_without_files() -> Result<()> {
+        let cluster = MockCluster::new(1)?;
+        cluster.create_topic("landed", 1, 1)?;
+        produce(&cluster, "landed", &["not json", "still not json"]).await?;
+
+        let mut events = KafkaFileEvents::new(KafkaFileEventsOptions {
+            brokers: cluster.bootstrap_servers(),
+            topics: vec!["landed".to_string()],
+            payload: PayloadFormat::JsonPointer("/path".to_string()),
+            poll_timeout: Duration::from_secs(1),
+            ..Default::default()
+        })?;
+        let partition = ("landed".to_string(), 0);
+        while events.partitions.get(&partition).map_or(true, |offsets| offsets.next < 2) {
+            assert!(events.next_file().await?.is_empty());
+        }
+
+        // Skipped messages are committed as they are read, with no ack to wait for
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let tpl = events.consumer.committed(Duration::from_secs(5))?;
+        assert_eq!(tpl.find_partition("landed", 0).map(|p| p.offset()), Some(Offset::Offset(2)));
+        Ok(())
+    }
+
+    async fn produce(cluster: &MockCluster<'_, rdkafka::client::DefaultClientContext>, topic: &str, paths: &[&str]) -> Result<()> {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()?;
+        for path in paths {
+            producer
+                .send(FutureRecord::<(), _>::to(topic).payload(*path), Duration::from_secs(5))
+                .await
+                .map_err(|(err, _)| err)?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_exhausted_attempts() -> Result<()> {
+        let cluster = MockCluster::new(1)?;
+        cluster.create_topic("landed", 1, 1)?;
+        cluster.create_topic("dead", 1, 1)?;
+        produce(&cluster, "landed", &["bucket/a.parquet"]).await?;
+
+        // Without a dead letter topic the record stays uncommitted and its partition stops
+        let mut events = KafkaFileEvents::new(KafkaFileEventsOptions {
+            brokers: cluster.bootstrap_servers(),
+            topics: vec!["landed".to_string()],
+            payload: PayloadFormat::Path,
+            max_attempts: 1,
+            ..Default::default()
+        })?;
+        let mut files = vec![];
+        while files.is_empty() {
+            files = events.next_file().await?;
+        }
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        assert_eq!(events.partitions[&("landed".to_string(), 0)].committable(), 0);
+        assert!(events.paused().contains(&("landed".to_string(), 0)));
+        drop(events);
+
+        // With one the record is written there and committed
+        let mut events = KafkaFileEvents::new(KafkaFileEventsOptions {
+            brokers: cluster.bootstrap_servers(),
+            topics: vec!["landed".to_string()],
+            payload: PayloadFormat::Path,
+            max_attempts: 1,
+            dead_letter_topic: Some("dead".to_string()),
+            ..Default::default()
+        })?;
+        let mut files = vec![];
+        while files.is_empty() {
+            files = events.next_file().await?;
+        }
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        assert_eq!(events.partitions[&("landed".to_string(), 0)].committable(), 1);
+        assert!(events.paused().is_empty());
+
+        let mut dead = KafkaFileEvents::new(KafkaFileEventsOptions {
+            brokers: cluster.bootstrap_servers(),
+            group_id: "dead-letters".to_string(),
+            topics: vec!["dead".to_string()],
+            payload: PayloadFormat::JsonPointer("/path".to_string()),
+            ..Default::default()
+        })?;
+        let mut dead_files = vec![];
+        while dead_files.is_empty() {
+            dead_files = dead.next_file().await?;
+        }
+        assert_eq!(dead_files[0].path.as_ref(), "bucket/a.parquet");
+        Ok(())
+    }
+}
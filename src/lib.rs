@@ -1,17 +1,52 @@
 #![feature(async_fn_in_trait)]
 
 use anyhow::Result;
-use object_store::path::Path;
+
+pub use event::{AckToken, FileEvent, FileEventKind};
 
 pub mod aws;
+#[cfg(feature = "azure")]
+pub mod azure;
+pub mod filter;
+#[cfg(feature = "gcp")]
+pub mod gcp;
+pub mod in_flight;
+pub mod inventory;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod listing;
 pub mod local;
+pub mod manifest;
+pub mod marker;
+pub mod merge;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod payload;
 pub mod processor;
+#[cfg(feature = "redis")]
+pub mod redis_stream;
+pub mod retry;
 pub mod uc;
 pub mod hdfs;
+pub mod state;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
+mod event;
 #[cfg(test)]
 mod test_utils;
 
 pub trait FileEvents {
-    async fn next_file(&mut self) -> Result<Vec<Path>>;
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>>;
+
+    /// Called once `event` has been committed to the table, the source may now forget about it.
+    async fn ack(&mut self, _event: &FileEvent) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when `event` could not be committed because of `error`, the source should make it
+    /// available again or give up on it.
+    async fn nack(&mut self, _event: &FileEvent, _error: &anyhow::Error) -> Result<()> {
+        Ok(())
+    }
 }
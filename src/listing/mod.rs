@@ -0,0 +1,378 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use object_store::path::Path;
+use object_store::{DynObjectStore, ObjectMeta, ObjectStore};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::retry::{Attempt, RetryQueue};
+use crate::{state, AckToken, FileEvent, FileEvents};
+
+#[derive(Debug, Clone)]
+pub struct ListingFileEventsOptions {
+    pub prefix: Option<Path>,
+    /// File the listing progress is kept in, only kept in memory when unset.
+    pub state_file: Option<PathBuf>,
+    /// Keys are written in lexical order, e.g. under date partitions, so anything sorting before
+    /// the last committed key is old. Lets whole prefixes be skipped instead of listed.
+    pub ordered_keys: bool,
+    /// Most files handed out per poll, in key order.
+    pub max_files: Option<usize>,
+    /// Times a file is tried before it is parked in the state file, see [ListingFileEvents::failed].
+    pub max_attempts: u32,
+    /// How long a failed file waits before it is handed out again, doubling with every failure.
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+}
+
+impl Default for ListingFileEventsOptions {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            state_file: None,
+            ordered_keys: false,
+            max_files: None,
+            max_attempts: 5,
+            retry_backoff: Duration::from_secs(30),
+            max_retry_backoff: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ListingState {
+    /// With ordered keys, every key up to and including this one is committed.
+    watermark: Option<String>,
+    /// Committed keys not covered by the watermark.
+    committed: BTreeSet<String>,
+    /// Keys that ran out of attempts and why, committed so they are skipped from then on.
+    #[serde(default)]
+    failed: BTreeMap<String, String>,
+}
+
+impl ListingState {
+    fn is_committed(&self, key: &str) -> bool {
+        self.watermark.as_deref().map_or(false, |w| key <= w) || self.committed.contains(key)
+    }
+}
+
+/// Finds new files by listing a prefix of any object store, for stores that can't send notifications.
+pub struct ListingFileEvents {
+    storage: Arc<DynObjectStore>,
+    opts: ListingFileEventsOptions,
+    state: ListingState,
+    in_flight: BTreeSet<String>,
+    /// Nacked keys waiting to be handed out again by `retries`, left out of listings until then.
+    /// With ordered keys the watermark can't pass them.
+    retrying: BTreeSet<String>,
+    retries: RetryQueue,
+}
+
+impl ListingFileEvents {
+    pub fn new(storage: impl ObjectStore, opts: ListingFileEventsOptions) -> Result<Self> {
+        Self::with_storage(Arc::new(storage), opts)
+    }
+
+    pub fn with_storage(storage: Arc<DynObjectStore>, opts: ListingFileEventsOptions) -> Result<Self> {
+        let state = match &opts.state_file {
+            Some(location) => state::load(location)?,
+            None => ListingState::default(),
+        };
+        let retries = RetryQueue::new(opts.max_attempts).with_backoff(opts.retry_backoff, opts.max_retry_backoff);
+        Ok(Self {
+            storage,
+            opts,
+            state,
+            in_flight: BTreeSet::new(),
+            retrying: BTreeSet::new(),
+            retries,
+        })
+    }
+
+    /// Keys that ran out of attempts, with their last error, so they can be looked into and
+    /// ingested by hand.
+    pub fn failed(&self) -> &BTreeMap<String, String> {
+        &self.state.failed
+    }
+
+    fn source(&self) -> String {
+        self.opts.prefix.as_ref().map(|p| p.to_string()).unwrap_or_default()
+    }
+
+    /// Lists everything under the prefix, or only what sorts after the watermark with ordered keys.
+    async fn list(&self) -> Result<Vec<ObjectMeta>> {
+        let prefix = self.opts.prefix.as_ref();
+        let watermark = self.state.watermark.as_deref().filter(|_| self.opts.ordered_keys);
+        let Some(watermark) = watermark else {
+            return Ok(self.storage.list(prefix).await?.try_collect().await?);
+        };
+
+        let mut objects = vec![];
+        let mut prefixes = vec![prefix.cloned()];
+        while let Some(prefix) = prefixes.pop() {
+            let listing = self.storage.list_with_delimiter(prefix.as_ref()).await?;
+            objects.extend(listing.objects.into_iter().filter(|o| o.location.as_ref() > watermark));
+            // A prefix that sorts before the watermark, without the watermark inside it, only
+            // holds keys that sort before the watermark too
+            prefixes.extend(
+                listing
+                    .common_prefixes
+                    .into_iter()
+                    .filter(|p| p.as_ref() >= watermark || watermark.starts_with(p.as_ref()))
+                    .map(Some),
+            );
+        }
+        Ok(objects)
+    }
+
+    fn file_event(&self, meta: ObjectMeta) -> FileEvent {
+        let key = meta.location.to_string();
+        FileEvent {
+            size: Some(meta.size),
+            event_time: Some(meta.last_modified),
+            ack_token: AckToken::new(key),
+            ..FileEvent::new(meta.location, self.source())
+        }
+    }
+
+    /// Moves the watermark up to just below the first key still in flight or waiting on a retry.
+    fn advance_watermark(&mut self) {
+        if !self.opts.ordered_keys {
+            return;
+        }
+        let floor = self.in_flight.iter().chain(self.retrying.iter()).min().cloned();
+        let ready = match floor {
+            Some(floor) => self
+                .state
+                .committed
+                .range::<String, _>(..floor)
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => std::mem::take(&mut self.state.committed).into_iter().collect(),
+        };
+        if let Some(last) = ready.last() {
+            self.state.watermark = Some(last.clone());
+        }
+        for key in &ready {
+            self.state.committed.remove(key);
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.opts.state_file {
+            Some(location) => state::store(location, &self.state),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FileEvents for ListingFileEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut objects = self.list().await?;
+        objects.sort_by(|a, b| a.location.cmp(&b.location));
+
+        let listed = objects.iter().map(|o| o.location.to_string()).collect::<HashSet<_>>();
+        if !self.opts.ordered_keys {
+            // Forget committed keys that have since been deleted so the state doesn't grow forever
+            self.state.committed.retain(|key| listed.contains(key));
+        }
+        // A nacked key that has since been deleted won't come back, so it stops holding the watermark
+        self.retrying.retain(|key| listed.contains(key));
+
+        // Failed files whose backoff has passed go first
+        let mut events = vec![];
+        for event in self.retries.take() {
+            let key = event.ack_token.as_str();
+            if !self.retrying.remove(key) {
+                self.retries.ack(&event);
+                continue;
+            }
+            self.in_flight.insert(key.to_string());
+            events.push(event);
+        }
+
+        let max_files = self.opts.max_files.unwrap_or(usize::MAX).saturating_sub(events.len());
+        let new_objects = objects
+            .into_iter()
+            .filter(|o| {
+                let key = o.location.as_ref();
+                !self.state.is_committed(key) && !self.in_flight.contains(key) && !self.retrying.contains(key)
+            })
+            .take(max_files)
+            .collect::<Vec<_>>();
+
+        for object in new_objects {
+            let event = self.file_event(object);
+            self.in_flight.insert(event.ack_token.as_str().to_string());
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        let key = event.ack_token.as_str();
+        self.retries.ack(event);
+        if !self.in_flight.remove(key) {
+            return Ok(());
+        }
+        self.state.committed.insert(key.to_string());
+        self.advance_watermark();
+        self.save()
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        let key = event.ack_token.as_str();
+        if !self.in_flight.remove(key) {
+            return Ok(());
+        }
+        let Attempt::Exhausted(attempts) = self.retries.nack(event) else {
+            self.retrying.insert(key.to_string());
+            return Ok(());
+        };
+        warn!("{} failed {} times, parking it in the state file: {:#}", event.path, attempts, error);
+        self.state.failed.insert(key.to_string(), format!("{:#}", error));
+        self.state.committed.insert(key.to_string());
+        self.advance_watermark();
+        self.save()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs;
+
+    use object_store::local::LocalFileSystem;
+
+    use super::*;
+
+    fn write(root: &std::path::Path, key: &str) -> Result<()> {
+        let file = root.join(key);
+        fs::create_dir_all(file.parent().unwrap())?;
+        fs::write(file, key)?;
+        Ok(())
+    }
+
+    async fn ack_all(events: &mut ListingFileEvents) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        for event in events.next_file().await? {
+            events.ack(&event).await?;
+            keys.push(event.path.to_string());
+        }
+        Ok(keys)
+    }
+
+    #[tokio::test]
+    pub async fn test_listing() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let state_file = tempfile::tempdir()?.into_path().join("listing.json");
+        write(root.path(), "a/1.parquet")?;
+        write(root.path(), "b/2.parquet")?;
+
+        let opts = ListingFileEventsOptions {
+            state_file: Some(state_file.clone()),
+            ..Default::default()
+        };
+        let mut events = ListingFileEvents::new(LocalFileSystem::new_with_prefix(root.path())?, opts.clone())?;
+        assert_eq!(ack_all(&mut events).await?, vec!["a/1.parquet", "b/2.parquet"]);
+        assert!(events.next_file().await?.is_empty());
+
+        write(root.path(), "a/0.parquet")?;
+        assert_eq!(ack_all(&mut events).await?, vec!["a/0.parquet"]);
+
+        // A restart only sees what's new since
+        write(root.path(), "c/3.parquet")?;
+        let mut events = ListingFileEvents::new(LocalFileSystem::new_with_prefix(root.path())?, opts)?;
+        assert_eq!(ack_all(&mut events).await?, vec!["c/3.parquet"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_ordered_listing() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write(root.path(), "date=2023-01-01/1.parquet")?;
+        write(root.path(), "date=2023-01-02/1.parquet")?;
+        write(root.path(), "date=2023-01-02/2.parquet")?;
+
+        let opts = ListingFileEventsOptions {
+            ordered_keys: true,
+            max_files: Some(2),
+            retry_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut events = ListingFileEvents::new(LocalFileSystem::new_with_prefix(root.path())?, opts)?;
+
+        let first = events.next_file().await?;
+        assert_eq!(first.len(), 2);
+        // The second file fails, so the watermark can't move past it
+        events.ack(&first[0]).await?;
+        events.nack(&first[1], &anyhow::anyhow!("failed")).await?;
+        assert_eq!(events.state.watermark.as_deref(), Some("date=2023-01-01/1.parquet"));
+
+        assert_eq!(ack_all(&mut events).await?, vec!["date=2023-01-02/1.parquet", "date=2023-01-02/2.parquet"]);
+        assert_eq!(events.state.watermark.as_deref(), Some("date=2023-01-02/2.parquet"));
+
+        // Keys sorting before the watermark are considered done
+        write(root.path(), "date=2022-12-31/1.parquet")?;
+        write(root.path(), "date=2023-01-03/1.parquet")?;
+        assert_eq!(ack_all(&mut events).await?, vec!["date=2023-01-03/1.parquet"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_ordered_listing_retry() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write(root.path(), "date=2023-01-01/1.parquet")?;
+        write(root.path(), "date=2023-01-02/1.parquet")?;
+
+        let opts = ListingFileEventsOptions {
+            ordered_keys: true,
+            retry_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut events = ListingFileEvents::new(LocalFileSystem::new_with_prefix(root.path())?, opts)?;
+
+        // The first file fails and the second is committed, the failed one still holds the watermark
+        let first = events.next_file().await?;
+        events.nack(&first[0], &anyhow::anyhow!("failed")).await?;
+        events.ack(&first[1]).await?;
+        assert_eq!(events.state.watermark, None);
+
+        assert_eq!(ack_all(&mut events).await?, vec!["date=2023-01-01/1.parquet"]);
+        assert_eq!(events.state.watermark.as_deref(), Some("date=2023-01-02/1.parquet"));
+        assert!(events.next_file().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_listing_retry_backoff() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write(root.path(), "a/1.parquet")?;
+
+        let opts = ListingFileEventsOptions {
+            max_attempts: 2,
+            retry_backoff: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut events = ListingFileEvents::new(LocalFileSystem::new_with_prefix(root.path())?, opts)?;
+
+        // A failed file isn't listed again straight away, only once its backoff has passed
+        let files = events.next_file().await?;
+        events.nack(&files[0], &anyhow::anyhow!("failed")).await?;
+        assert!(events.next_file().await?.is_empty());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let retried = events.next_file().await?;
+        assert_eq!(retried.len(), 1);
+
+        // Out of attempts, it is parked rather than retried forever
+        events.nack(&retried[0], &anyhow::anyhow!("failed again")).await?;
+        assert_eq!(events.failed().get("a/1.parquet").map(String::as_str), Some("failed again"));
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(events.next_file().await?.is_empty());
+        Ok(())
+    }
+}
@@ -1,56 +1,511 @@
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
-use notify::event::CreateKind;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode};
 use notify::RecursiveMode::Recursive;
-use notify::{Config, Event, EventHandler, EventKind, RecommendedWatcher, Watcher};
+use notify::{Config, Event, EventHandler, EventKind, PollWatcher, RecommendedWatcher, Watcher};
 use object_store::path::Path;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::Instant;
+use tracing::warn;
 
-use crate::FileEvents;
+use crate::{AckToken, FileEvent, FileEvents};
+
+/// When a file counts as fully written.
+#[derive(Debug, Clone, Copy)]
+pub enum ReadyPolicy {
+    /// Once the writer closes it, only reported by inotify on Linux.
+    CloseWrite,
+    /// Once its size and modification time haven't changed for this long.
+    QuietPeriod(Duration),
+}
+
+impl Default for ReadyPolicy {
+    fn default() -> Self {
+        Self::QuietPeriod(Duration::from_secs(5))
+    }
+}
+
+/// How changes in the watched directory are noticed.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum WatcherKind {
+    /// The platform's native notifications, e.g. inotify.
+    #[default]
+    Native,
+    /// Walks the directory on an interval, for NFS/SMB mounts and volumes that don't send native
+    /// notifications. Only works with [ReadyPolicy::QuietPeriod].
+    Poll(Duration),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalFileEventsOptions {
+    pub location: PathBuf,
+    pub ready: ReadyPolicy,
+    pub watcher: WatcherKind,
+    /// Queue files already in `location` on startup, not just ones created afterwards.
+    pub scan_existing: bool,
+    /// Ingested files are moved here, keeping their path relative to `location`. Relative
+    /// directories are resolved against `location` and never watched.
+    pub processed_dir: Option<PathBuf>,
+    /// Files that failed to ingest are moved here, same rules as `processed_dir`.
+    pub failed_dir: Option<PathBuf>,
+}
+
+impl LocalFileEventsOptions {
+    pub fn processed(location: PathBuf) -> Self {
+        Self {
+            processed_dir: Some(PathBuf::from("processed")),
+            failed_dir: Some(PathBuf::from("failed")),
+            scan_existing: true,
+            location,
+            ..Default::default()
+        }
+    }
+}
+
+/// Moves `from` to `to`, copying when they are on different filesystems.
+fn move_file(from: &FsPath, to: &FsPath) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Err(err.into()),
+        Err(_) => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+    }
+}
+
+/// Events buffered between the watcher thread and the source, past this we fall back to a rescan.
+const EVENT_BUFFER: usize = 100;
 
 struct EventCallback {
     sender: Sender<Event>,
+    rescan: Arc<AtomicBool>,
+    wake: Arc<tokio::sync::Notify>,
+}
+
+impl EventCallback {
+    /// Events were lost, so the next poll walks the directory to find whatever they were about.
+    fn request_rescan(&self) {
+        self.rescan.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+    }
 }
 
 impl EventHandler for EventCallback {
     fn handle_event(&mut self, event: notify::Result<Event>) {
-        if let Ok(evt) = event {
-            match evt.kind {
-                EventKind::Create(CreateKind::File) => {
-                    self.sender.blocking_send(evt).expect("Don't error plz :'(");
+        let evt = match event {
+            Ok(evt) => evt,
+            Err(err) => {
+                warn!("File watcher error, rescanning: {:?}", err);
+                self.request_rescan();
+                return;
+            }
+        };
+        if evt.need_rescan() {
+            self.request_rescan();
+            return;
+        }
+
+        match evt.kind {
+            EventKind::Create(CreateKind::File | CreateKind::Any)
+            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Metadata(_) | ModifyKind::Any | ModifyKind::Name(_))
+            | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                match self.sender.try_send(evt) {
+                    Ok(_) => {}
+                    // Never block the watcher thread, catching up with a rescan is cheaper
+                    Err(TrySendError::Full(_)) => self.request_rescan(),
+                    // We're shutting down
+                    Err(TrySendError::Closed(_)) => {}
                 }
-                _ => {}
             }
+            _ => {}
         }
     }
 }
 
+/// A file that is still being watched for changes under [ReadyPolicy::QuietPeriod].
+struct Candidate {
+    size: u64,
+    modified: Option<SystemTime>,
+    since: Instant,
+}
+
 pub struct LocalFileEvents {
+    opts: LocalFileEventsOptions,
     events: Receiver<Event>,
-    watcher: RecommendedWatcher,
+    watcher: Box<dyn Watcher + Send>,
+    rescan: Arc<AtomicBool>,
+    wake: Arc<tokio::sync::Notify>,
+    candidates: HashMap<PathBuf, Candidate>,
+    ready: Vec<PathBuf>,
+    // Files handed out and not archived, so a rescan doesn't hand them out again
+    emitted: HashSet<PathBuf>,
 }
 
 impl LocalFileEvents {
-    pub fn new(location: PathBuf) -> Result<Self> {
-        let (sender, events) = channel::<Event>(100);
-        let callback = EventCallback { sender };
-        let mut watcher = RecommendedWatcher::new(callback, Config::default())?;
-        watcher.watch(location.as_path(), Recursive)?;
-        Ok(Self { events, watcher })
+    pub fn new(mut opts: LocalFileEventsOptions) -> Result<Self> {
+        // Everything is kept absolute so paths from the watcher, the scan and the archive line up
+        opts.location = opts.location.canonicalize()?;
+        for dir in [&mut opts.processed_dir, &mut opts.failed_dir].into_iter().flatten() {
+            *dir = opts.location.join(&dir);
+        }
+
+        let (sender, events) = channel::<Event>(EVENT_BUFFER);
+        let rescan = Arc::new(AtomicBool::new(false));
+        let wake = Arc::new(tokio::sync::Notify::new());
+        let callback = EventCallback {
+            sender,
+            rescan: rescan.clone(),
+            wake: wake.clone(),
+        };
+        let mut watcher: Box<dyn Watcher + Send> = match (opts.watcher, opts.ready) {
+            (WatcherKind::Native, _) => Box::new(RecommendedWatcher::new(callback, Config::default())?),
+            (WatcherKind::Poll(interval), ReadyPolicy::QuietPeriod(_)) => {
+                Box::new(PollWatcher::new(callback, Config::default().with_poll_interval(interval))?)
+            }
+            (WatcherKind::Poll(_), ReadyPolicy::CloseWrite) => {
+                return Err(anyhow!("A polling watcher never sees files being closed, use a quiet period instead"));
+            }
+        };
+        watcher.watch(opts.location.as_path(), Recursive)?;
+        let mut local = Self {
+            opts,
+            events,
+            watcher,
+            rescan,
+            wake,
+            candidates: HashMap::new(),
+            ready: vec![],
+            emitted: HashSet::new(),
+        };
+        if local.opts.scan_existing {
+            local.scan()?;
+        }
+        Ok(local)
+    }
+
+    /// Whether `file` lives in one of the archive directories and should never be picked up.
+    fn is_archived(&self, file: &FsPath) -> bool {
+        [&self.opts.processed_dir, &self.opts.failed_dir]
+            .into_iter()
+            .flatten()
+            .any(|dir| file.starts_with(dir))
+    }
+
+    /// Queues every file under `location` that hasn't been handed out yet, as though each had
+    /// just been written.
+    fn scan(&mut self) -> Result<()> {
+        // Forget anything that has since been removed, nothing else shrinks `emitted`
+        self.emitted.retain(|file| file.is_file());
+        let mut dirs = vec![self.opts.location.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if self.is_archived(&path) || self.emitted.contains(&path) {
+                    continue;
+                }
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if matches!(self.opts.ready, ReadyPolicy::CloseWrite) {
+                    self.mark_ready(path);
+                } else {
+                    self.touch(path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn archive(&mut self, event: &FileEvent, dir: Option<PathBuf>) -> Result<()> {
+        let Some(dir) = dir else {
+            return Ok(());
+        };
+        let file = PathBuf::from(event.ack_token.as_str());
+        self.emitted.remove(&file);
+        let relative = file.strip_prefix(&self.opts.location).unwrap_or(&file);
+        move_file(&file, &dir.join(relative))
+    }
+
+    fn file_event(&self, file: PathBuf) -> Result<FileEvent> {
+        let path = Path::from_filesystem_path(&file)?;
+        Ok(FileEvent {
+            size: fs::metadata(&file).ok().map(|m| m.len() as usize),
+            event_time: Some(Utc::now()),
+            ack_token: AckToken::new(file.to_string_lossy()),
+            ..FileEvent::new(path, self.opts.location.to_string_lossy())
+        })
+    }
+
+    fn handle(&mut self, evt: Event) {
+        let close_write = matches!(self.opts.ready, ReadyPolicy::CloseWrite);
+        let files = match evt.kind {
+            // The usual atomic write, a file renamed into place is complete as soon as it shows up
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let Some(file) = evt.paths.into_iter().nth(1) {
+                    self.mark_ready(file);
+                }
+                return;
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                evt.paths.into_iter().for_each(|file| self.mark_ready(file));
+                return;
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => return,
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) if close_write => {
+                evt.paths.into_iter().for_each(|file| self.mark_ready(file));
+                return;
+            }
+            _ if close_write => return,
+            _ => evt.paths,
+        };
+        for file in files {
+            self.touch(file);
+        }
+    }
+
+    fn mark_ready(&mut self, file: PathBuf) {
+        if file.is_file() && !self.is_archived(&file) {
+            self.candidates.remove(&file);
+            self.ready.push(file);
+        }
+    }
+
+    /// Starts or restarts the quiet period of `file` if it changed since we last looked.
+    fn touch(&mut self, file: PathBuf) {
+        let Ok(meta) = fs::metadata(&file) else {
+            self.candidates.remove(&file);
+            return;
+        };
+        if !meta.is_file() || self.is_archived(&file) {
+            return;
+        }
+        let (size, modified) = (meta.len(), meta.modified().ok());
+        match self.candidates.get(&file) {
+            Some(c) if c.size == size && c.modified == modified => {}
+            _ => {
+                self.candidates.insert(file, Candidate { size, modified, since: Instant::now() });
+            }
+        }
+    }
+
+    /// Moves candidates whose quiet period has passed without changes over to `ready`.
+    fn check_candidates(&mut self) {
+        let ReadyPolicy::QuietPeriod(quiet) = self.opts.ready else {
+            return;
+        };
+        let files = self.candidates.keys().cloned().collect::<Vec<_>>();
+        for file in files {
+            self.touch(file.clone());
+            if let Some(c) = self.candidates.get(&file) {
+                if c.since.elapsed() >= quiet {
+                    self.candidates.remove(&file);
+                    self.ready.push(file);
+                }
+            }
+        }
+    }
+
+    /// How long until the next candidate could be ready, `None` when there are none to wait on.
+    fn next_deadline(&self) -> Option<Duration> {
+        let ReadyPolicy::QuietPeriod(quiet) = self.opts.ready else {
+            return None;
+        };
+        self.candidates
+            .values()
+            .map(|c| quiet.saturating_sub(c.since.elapsed()))
+            .min()
     }
 }
 
 impl FileEvents for LocalFileEvents {
-    async fn next_file(&mut self) -> Result<Vec<Path>> {
-        if let Some(evt) = self.events.recv().await {
-            Ok(evt
-                .paths
-                .into_iter()
-                .flat_map(Path::from_filesystem_path)
-                .collect::<Vec<_>>())
-        } else {
-            Ok(vec![])
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        loop {
+            while let Ok(evt) = self.events.try_recv() {
+                self.handle(evt);
+            }
+            if self.rescan.swap(false, Ordering::SeqCst) {
+                warn!("Missed file events in {}, rescanning", self.opts.location.display());
+                self.scan()?;
+            }
+            self.check_candidates();
+            if !self.ready.is_empty() {
+                let mut seen = HashSet::new();
+                let ready = std::mem::take(&mut self.ready);
+                let ready = ready
+                    .into_iter()
+                    .filter(|file| seen.insert(file.clone()))
+                    .collect::<Vec<_>>();
+                self.emitted.extend(ready.iter().cloned());
+                return Ok(ready
+                    .into_iter()
+                    .flat_map(|file| self.file_event(file))
+                    .collect::<Vec<_>>());
+            }
+
+            let deadline = self.next_deadline();
+            let evt = tokio::select! {
+                evt = self.events.recv() => evt,
+                _ = self.wake.notified() => continue,
+                _ = tokio::time::sleep(deadline.unwrap_or_default()), if deadline.is_some() => continue,
+            };
+            match evt {
+                Some(evt) => self.handle(evt),
+                None => return Ok(vec![]),
+            }
+        }
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        self.archive(event, self.opts.processed_dir.clone())
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        if self.opts.failed_dir.is_none() {
+            // Nowhere to put it, so let the next rescan try it again
+            self.emitted.remove(&PathBuf::from(event.ack_token.as_str()));
+            return Ok(());
         }
+        warn!("Moving {} to the failed directory: {:#}", event.path, error);
+        self.archive(event, self.opts.failed_dir.clone())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_quiet_period() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut events = LocalFileEvents::new(LocalFileEventsOptions {
+            location: dir.path().to_path_buf(),
+            ready: ReadyPolicy::QuietPeriod(Duration::from_millis(300)),
+            ..Default::default()
+        })?;
+
+        let file = dir.path().join("part-0000.parquet");
+        fs::write(&file, b"half")?;
+        let writer = tokio::spawn({
+            let file = file.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                fs::write(&file, b"half and the rest").unwrap();
+            }
+        });
+
+        let files = timeout(Duration::from_secs(5), events.next_file()).await??;
+        writer.await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, Path::from_filesystem_path(&file)?);
+        assert_eq!(files[0].size, Some(17));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_rename_into_place() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let staging = tempfile::tempdir()?;
+        let mut events = LocalFileEvents::new(LocalFileEventsOptions {
+            location: dir.path().to_path_buf(),
+            ready: ReadyPolicy::QuietPeriod(Duration::from_secs(60)),
+            ..Default::default()
+        })?;
+
+        let staged = staging.path().join("part-0000.parquet");
+        fs::write(&staged, b"complete")?;
+        let file = dir.path().join("part-0000.parquet");
+        fs::rename(&staged, &file)?;
+
+        let files = timeout(Duration::from_secs(5), events.next_file()).await??;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, Path::from_filesystem_path(&file)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_scan_and_archive() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("a"))?;
+        fs::write(dir.path().join("a/good.parquet"), b"good")?;
+        fs::write(dir.path().join("bad.parquet"), b"bad")?;
+        fs::create_dir_all(dir.path().join("processed"))?;
+        fs::write(dir.path().join("processed/old.parquet"), b"old")?;
+
+        let mut events = LocalFileEvents::new(LocalFileEventsOptions {
+            ready: ReadyPolicy::QuietPeriod(Duration::from_millis(10)),
+            ..LocalFileEventsOptions::processed(dir.path().to_path_buf())
+        })?;
+        let mut files = timeout(Duration::from_secs(5), events.next_file()).await??;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files.len(), 2);
+
+        events.ack(&files[0]).await?;
+        events.nack(&files[1], &anyhow::anyhow!("not parquet")).await?;
+        assert!(dir.path().join("processed/a/good.parquet").is_file());
+        assert!(dir.path().join("failed/bad.parquet").is_file());
+        assert!(!dir.path().join("a/good.parquet").exists());
+        assert!(!dir.path().join("bad.parquet").exists());
+
+        // Moving files into the archive doesn't make them show up again
+        let more = timeout(Duration::from_millis(500), events.next_file()).await;
+        assert!(more.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_poll_watcher() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut events = LocalFileEvents::new(LocalFileEventsOptions {
+            location: dir.path().to_path_buf(),
+            ready: ReadyPolicy::QuietPeriod(Duration::from_millis(100)),
+            watcher: WatcherKind::Poll(Duration::from_millis(50)),
+            ..Default::default()
+        })?;
+
+        let file = dir.path().join("part-0000.parquet");
+        fs::write(&file, b"complete")?;
+        let files = timeout(Duration::from_secs(5), events.next_file()).await??;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, Path::from_filesystem_path(&file)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_rescan() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut events = LocalFileEvents::new(LocalFileEventsOptions {
+            location: dir.path().to_path_buf(),
+            ready: ReadyPolicy::QuietPeriod(Duration::from_millis(10)),
+            ..Default::default()
+        })?;
+        fs::write(dir.path().join("seen.parquet"), b"seen")?;
+        let first = timeout(Duration::from_secs(5), events.next_file()).await??;
+        assert_eq!(first.len(), 1);
+
+        // Pretend the watcher overflowed while this one was written
+        while events.events.try_recv().is_ok() {}
+        events.watcher.unwatch(&events.opts.location)?;
+        fs::write(dir.path().join("missed.parquet"), b"missed")?;
+        events.rescan.store(true, Ordering::SeqCst);
+
+        let files = timeout(Duration::from_secs(5), events.next_file()).await??;
+        assert_eq!(files.len(), 1);
+        assert!(files[0].path.as_ref().ends_with("missed.parquet"));
+        Ok(())
     }
 }
@@ -1,6 +1,9 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{ArgGroup, Parser};
 use object_store::ObjectStore;
 use tokio::time::interval;
+use tracing::error;
 
 use delta_file_ingest::aws::sqs::SqsEvents;
 use delta_file_ingest::aws::SqsEventOptions;
@@ -10,12 +13,17 @@ use delta_file_ingest::uc::{UnityCatalogApi, UnityCatalogClient, UnityCatalogOpt
 
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about)]
+#[command(group(ArgGroup::new("queue").required(true).args(["queue_name", "queue_url"])))]
 pub struct RunOptions {
     #[arg(short, long)]
     table_name: String,
     // Event Processor Opts
     #[arg(long, default_missing_value = "10s")]
     poll_time: humantime::Duration,
+    #[arg(long)]
+    mirror_removals: bool,
+    #[arg(long)]
+    verify_etag: bool,
 
     // UC Options
     #[arg(long)]
@@ -28,21 +36,82 @@ pub struct RunOptions {
     default_schema: String,
     // Message Queue Options
     #[arg(long)]
-    queue_name: String,
+    queue_name: Option<String>,
+    #[arg(long)]
+    queue_url: Option<String>,
+    #[arg(long)]
+    raw_message_delivery: bool,
+    #[arg(long, default_value = "20s")]
+    wait_time: humantime::Duration,
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(i32).range(1..=10))]
+    max_messages: i32,
+    #[arg(long, default_value_t = 1)]
+    receives_per_poll: usize,
+    #[arg(long)]
+    visibility_timeout: Option<humantime::Duration>,
+    #[arg(long, value_delimiter = ',')]
+    message_attribute_names: Vec<String>,
+    #[arg(long)]
+    visibility_heartbeat: Option<humantime::Duration>,
+    #[arg(long, default_value = "30s")]
+    retry_backoff: humantime::Duration,
+    #[arg(long, default_value = "15m")]
+    max_retry_backoff: humantime::Duration,
+    #[arg(long, requires = "dead_letter_queue_url")]
+    max_receive_count: Option<u32>,
+    #[arg(long)]
+    dead_letter_queue_url: Option<String>,
+    #[arg(long)]
+    sequencer_cache: Option<PathBuf>,
+    #[arg(long, default_value_t = 100_000)]
+    sequencer_cache_capacity: usize,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
     let RunOptions {
         table_name,
         poll_time,
+        mirror_removals,
+        verify_etag,
         db_api_host,
         db_api_token,
         default_catalog,
         default_schema,
         queue_name,
+        queue_url,
+        raw_message_delivery,
+        wait_time,
+        max_messages,
+        receives_per_poll,
+        visibility_timeout,
+        message_attribute_names,
+        visibility_heartbeat,
+        retry_backoff,
+        max_retry_backoff,
+        max_receive_count,
+        dead_letter_queue_url,
+        sequencer_cache,
+        sequencer_cache_capacity,
     } = RunOptions::parse();
-    let queue_options = SqsEventOptions { queue_name };
+    let queue_options = SqsEventOptions {
+        queue_name: queue_name.unwrap_or_default(),
+        queue_url,
+        raw_message_delivery,
+        wait_time: wait_time.into(),
+        max_messages,
+        receives_per_poll,
+        visibility_timeout: visibility_timeout.map(Into::into),
+        message_attribute_names,
+        heartbeat_interval: visibility_heartbeat.map(Into::into),
+        retry_backoff: retry_backoff.into(),
+        max_retry_backoff: max_retry_backoff.into(),
+        max_receive_count,
+        dead_letter_queue_url,
+        sequencer_cache,
+        sequencer_cache_capacity,
+    };
     let uc_options = UnityCatalogOptions {
         db_api_host,
         default_catalog,
@@ -51,12 +120,14 @@ async fn main() -> anyhow::Result<()> {
     };
     let event_proc_options = EventProcessorOptions {
         poll_time: poll_time.as_secs(),
+        mirror_removals,
+        verify_etag,
     };
 
     let uc = UnityCatalogClient::new(uc_options)?;
     let storage_location = uc.get_table_schema(table_name).await?.storage_location;
 
-    let events = setup_events(queue_options).await;
+    let events = setup_events(queue_options).await?;
     let storage = setup_storage();
     let table = deltalake::open_table(storage_location).await?;
     let mut event_processor = EventProcessor::new(events, storage, table, event_proc_options)?;
@@ -64,16 +135,16 @@ async fn main() -> anyhow::Result<()> {
     loop {
         let _ = interval(poll_time.into()).tick().await;
 
-        if let Err(_err) = event_processor.run().await {
-            panic!("Failed to process event: {:?}", _err);
+        if let Err(err) = event_processor.run().await {
+            error!("Failed to process events: {:?}", err);
         }
     }
 }
 
-pub async fn setup_events(opts: SqsEventOptions) -> impl FileEvents {
+pub async fn setup_events(opts: SqsEventOptions) -> anyhow::Result<impl FileEvents> {
     let shared_config = aws_config::load_from_env().await;
     let client = aws_sdk_sqs::Client::new(&shared_config);
-    SqsEvents::new(client, opts)
+    SqsEvents::new(client, opts).await
 }
 
 pub fn setup_storage() -> impl ObjectStore {
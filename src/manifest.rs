@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use globset::GlobSet;
+use md5::{Digest, Md5};
+use object_store::path::Path;
+use object_store::{DynObjectStore, ObjectStore};
+use serde::Deserialize;
+use tracing::{debug, error};
+
+use crate::filter::glob_set;
+use crate::{FileEvent, FileEventKind, FileEvents};
+
+/// One file named by a manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: Option<usize>,
+    /// Hex MD5 of the file's content.
+    pub md5: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonEntry {
+    Path(String),
+    File {
+        #[serde(alias = "key")]
+        path: String,
+        size: Option<usize>,
+        md5: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonManifest {
+    Files { files: Vec<JsonEntry> },
+    List(Vec<JsonEntry>),
+}
+
+/// Where a manifest line points. Lines starting with `/` or a scheme such as `s3://` are full
+/// paths, anything else is relative to the manifest's directory, subdirectories included.
+pub fn resolve(dir: &str, line: &str) -> String {
+    let line = line.trim();
+    let absolute = match line.split_once("://") {
+        Some((scheme, rest)) if !scheme.is_empty() && !scheme.contains('/') => Some(rest),
+        _ => line.strip_prefix('/'),
+    };
+    match absolute {
+        Some(path) => path.trim_start_matches('/').to_string(),
+        None if dir.is_empty() => line.to_string(),
+        None => format!("{}/{}", dir, line),
+    }
+}
+
+/// Reads a manifest written to `dir`. Either a newline separated list of files, or JSON: a list of
+/// files or an object with a `files` list, each file a path or `{"path", "size", "md5"}`.
+pub fn parse_manifest(dir: &str, bytes: &[u8]) -> Result<Vec<ManifestEntry>> {
+    let text = std::str::from_utf8(bytes)?;
+    let trimmed = text.trim_start();
+    if !trimmed.starts_with('{') && !trimmed.starts_with('[') {
+        return Ok(text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| ManifestEntry {
+                path: resolve(dir, line),
+                size: None,
+                md5: None,
+            })
+            .collect());
+    }
+
+    let entries = match serde_json::from_str(trimmed)? {
+        JsonManifest::Files { files } => files,
+        JsonManifest::List(files) => files,
+    };
+    Ok(entries
+        .into_iter()
+        .map(|entry| match entry {
+            JsonEntry::Path(path) => ManifestEntry {
+                path: resolve(dir, &path),
+                size: None,
+                md5: None,
+            },
+            JsonEntry::File { path, size, md5 } => ManifestEntry {
+                path: resolve(dir, &path),
+                size,
+                md5,
+            },
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone)]
+pub struct ManifestFileEventsOptions {
+    /// Globs naming the manifests, `*` stays within a directory and `**` crosses them.
+    pub manifest_names: Vec<String>,
+    /// Reject manifests whose files aren't the size they list.
+    pub verify_sizes: bool,
+    /// Reject manifests whose files don't match the MD5 they list, this reads every file twice.
+    pub verify_checksums: bool,
+    /// Hand on events that aren't manifests, by default they are acked and dropped since the
+    /// manifests already name the files to ingest.
+    pub pass_through: bool,
+}
+
+impl Default for ManifestFileEventsOptions {
+    fn default() -> Self {
+        Self {
+            manifest_names: vec![String::from("**/_manifest"), String::from("**/_manifest.json")],
+            verify_sizes: true,
+            verify_checksums: false,
+            pass_through: false,
+        }
+    }
+}
+
+/// A manifest whose files were handed out, waiting on the processor.
+struct ReleasedManifest {
+    manifest: FileEvent,
+    outstanding: usize,
+    failed: Option<anyhow::Error>,
+}
+
+/// Ingests the files named by each manifest the wrapped source hands out as one group, so they are
+/// committed in a single transaction. A manifest naming a file that is missing or doesn't match is
+/// rejected as a whole, and is only acked once every one of its files has been committed.
+pub struct ManifestFileEvents<F: FileEvents> {
+    events: F,
+    storage: Arc<DynObjectStore>,
+    opts: ManifestFileEventsOptions,
+    manifest_names: Option<GlobSet>,
+    released: HashMap<String, ReleasedManifest>,
+    rejected: u64,
+}
+
+impl<F: FileEvents> ManifestFileEvents<F> {
+    /// `storage` is where the manifests and the files they name are read from.
+    pub fn new(events: F, storage: Arc<DynObjectStore>, opts: ManifestFileEventsOptions) -> Result<Self> {
+        Ok(Self {
+            events,
+            storage,
+            manifest_names: glob_set(&opts.manifest_names)?,
+            opts,
+            released: HashMap::new(),
+            rejected: 0,
+        })
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.events
+    }
+
+    /// Manifests rejected so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    fn is_manifest(&self, event: &FileEvent) -> bool {
+        event.kind == FileEventKind::Created && self.manifest_names.as_ref().map_or(false, |names| names.is_match(event.path.as_ref()))
+    }
+
+    /// Checks `entry` is there and matches what the manifest says about it.
+    async fn check(&self, entry: &ManifestEntry) -> Result<Path> {
+        let path = Path::parse(&entry.path)?;
+        let meta = self
+            .storage
+            .head(&path)
+            .await
+            .map_err(|err| anyhow!("{} can't be found: {}", entry.path, err))?;
+        if let (true, Some(size)) = (self.opts.verify_sizes, entry.size) {
+            if size != meta.size {
+                return Err(anyhow!("{} is {} bytes but the manifest says {}", entry.path, meta.size, size));
+            }
+        }
+        if let (true, Some(md5)) = (self.opts.verify_checksums, &entry.md5) {
+            let bytes = self.storage.get(&path).await?.bytes().await?;
+            let digest = format!("{:x}", Md5::digest(&bytes));
+            if !digest.eq_ignore_ascii_case(md5) {
+                return Err(anyhow!("{} has MD5 {} but the manifest says {}", entry.path, digest, md5));
+            }
+        }
+        Ok(path)
+    }
+
+    /// The files `manifest` names, all of them or an error.
+    async fn expand(&self, manifest: &FileEvent) -> Result<Vec<FileEvent>> {
+        let dir = manifest.path.as_ref().rsplit_once('/').map_or("", |(dir, _)| dir);
+        let bytes = self.storage.get(&manifest.path).await?.bytes().await?;
+        let mut seen = HashSet::new();
+        let mut files = vec![];
+        for entry in parse_manifest(dir, &bytes)? {
+            if !seen.insert(entry.path.clone()) {
+                continue;
+            }
+            let path = self.check(&entry).await?;
+            // The processor checks these again when it reads the file, so only pass on what we verify
+            files.push(FileEvent {
+                size: entry.size.filter(|_| self.opts.verify_sizes),
+                e_tag: entry.md5.filter(|_| self.opts.verify_checksums),
+                event_time: manifest.event_time,
+                ack_token: manifest.ack_token.clone(),
+                group: Some(manifest.path.to_string()),
+                ..FileEvent::new(path, manifest.source.clone())
+            });
+        }
+        Ok(files)
+    }
+
+    /// Settles one file of a released manifest, the manifest follows once all of them are.
+    async fn settle(&mut self, group: &str, event: &FileEvent, error: Option<&anyhow::Error>) -> Result<()> {
+        let Some(released) = self.released.get_mut(group) else {
+            return Ok(());
+        };
+        if let (None, Some(error)) = (&released.failed, error) {
+            released.failed = Some(anyhow!("{} failed: {:#}", event.path, error));
+        }
+        released.outstanding = released.outstanding.saturating_sub(1);
+        if released.outstanding > 0 {
+            return Ok(());
+        }
+
+        let Some(ReleasedManifest { manifest, failed, .. }) = self.released.remove(group) else {
+            return Ok(());
+        };
+        match failed {
+            Some(err) => self.events.nack(&manifest, &err).await,
+            None => self.events.ack(&manifest).await,
+        }
+    }
+
+    /// The released manifest `event` belongs to, if any.
+    fn released_group(&self, event: &FileEvent) -> Option<String> {
+        event.group.clone().filter(|group| self.released.contains_key(group))
+    }
+}
+
+impl<F: FileEvents> FileEvents for ManifestFileEvents<F> {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut files = vec![];
+        for event in self.events.next_file().await? {
+            if !self.is_manifest(&event) {
+                if self.opts.pass_through {
+                    files.push(event);
+                } else if let Err(err) = self.events.ack(&event).await {
+                    error!("Unable to ack {}, which isn't a manifest: {:?}", event.path, err);
+                }
+                continue;
+            }
+            if self.released.contains_key(event.path.as_ref()) {
+                // The delivery that released it is the one acked once its files are committed
+                debug!("Manifest {} is already being ingested, acking the duplicate", event.path);
+                if self.released[event.path.as_ref()].manifest.ack_token != event.ack_token {
+                    self.events.ack(&event).await?;
+                }
+                continue;
+            }
+
+            match self.expand(&event).await {
+                Ok(parts) if parts.is_empty() => {
+                    debug!("Manifest {} names no files", event.path);
+                    self.events.ack(&event).await?;
+                }
+                Ok(parts) => {
+                    self.released.insert(
+                        event.path.to_string(),
+                        ReleasedManifest {
+                            outstanding: parts.len(),
+                            manifest: event,
+                            failed: None,
+                        },
+                    );
+                    files.extend(parts);
+                }
+                Err(err) => {
+                    error!("Rejecting manifest {}: {:?}", event.path, err);
+                    self.rejected += 1;
+                    self.events.nack(&event, &err).await?;
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        match self.released_group(event) {
+            Some(group) => self.settle(&group, event, None).await,
+            None => self.events.ack(event).await,
+        }
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        match self.released_group(event) {
+            Some(group) => self.settle(&group, event, Some(error)).await,
+            None => self.events.nack(event, error).await,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use object_store::memory::InMemory;
+
+    use crate::test_utils::RecordingFileEvents;
+    use crate::AckToken;
+
+    use super::*;
+
+    fn recording(paths: &[&str]) -> RecordingFileEvents {
+        RecordingFileEvents::new(paths.iter().map(|path| Path::from(*path)).collect())
+    }
+
+    #[test]
+    pub fn test_parse_manifest() -> Result<()> {
+        let listed = parse_manifest("out/a", b"part-0.parquet\n\n/other/part-1.parquet\nyear=2023/part-2.parquet\ns3://bucket/part-3.parquet\n")?;
+        let paths = listed.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["out/a/part-0.parquet", "other/part-1.parquet", "out/a/year=2023/part-2.parquet", "bucket/part-3.parquet"]);
+
+        let json = parse_manifest("out/a", br#"{"files": ["part-0.parquet", {"key": "part-1.parquet", "size": 10, "md5": "abc"}]}"#)?;
+        assert_eq!(json[0].path, "out/a/part-0.parquet");
+        assert_eq!(
+            json[1],
+            ManifestEntry {
+                path: "out/a/part-1.parquet".to_string(),
+                size: Some(10),
+                md5: Some("abc".to_string()),
+            }
+        );
+        assert_eq!(parse_manifest("", br#"["part-0.parquet"]"#)?[0].path, "part-0.parquet");
+        assert!(parse_manifest("", b"{not json").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_manifest_events() -> Result<()> {
+        let storage = Arc::new(InMemory::new());
+        storage.put(&Path::from("out/a/part-0.parquet"), "0123456789".into()).await?;
+        storage.put(&Path::from("out/a/part-1.parquet"), "0123456789".into()).await?;
+        let manifest = serde_json::json!({
+            "files": [
+                {"path": "part-0.parquet", "size": 10, "md5": format!("{:x}", Md5::digest(b"0123456789"))},
+                {"path": "part-1.parquet", "size": 10},
+            ]
+        });
+        storage.put(&Path::from("out/a/_manifest.json"), manifest.to_string().into()).await?;
+        storage.put(&Path::from("out/b/_manifest"), "part-0.parquet\npart-1.parquet\n".into()).await?;
+        storage.put(&Path::from("out/b/part-0.parquet"), "0123456789".into()).await?;
+        storage.put(&Path::from("out/c/_manifest.json"), r#"[{"path": "part-0.parquet", "size": 5}]"#.into()).await?;
+        storage.put(&Path::from("out/c/part-0.parquet"), "0123456789".into()).await?;
+
+        let source = recording(&["out/a/part-0.parquet", "out/a/_manifest.json", "out/b/_manifest", "out/c/_manifest.json"]);
+        let mut events = ManifestFileEvents::new(
+            source,
+            storage,
+            ManifestFileEventsOptions {
+                verify_checksums: true,
+                ..Default::default()
+            },
+        )?;
+        let files = events.next_file().await?;
+        let paths = files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["out/a/part-0.parquet", "out/a/part-1.parquet"]);
+        assert!(files.iter().all(|file| file.group.as_deref() == Some("out/a/_manifest.json")));
+        assert_eq!(files[0].size, Some(10));
+        assert!(files[0].e_tag.is_some());
+
+        // A missing file and a wrong size reject the whole manifest
+        assert_eq!(events.rejected(), 2);
+        assert_eq!(events.inner().nacked, vec![Path::from("out/b/_manifest"), Path::from("out/c/_manifest.json")]);
+        // Data files the source hands out itself are left to their manifest
+        assert_eq!(events.inner().acked, vec![Path::from("out/a/part-0.parquet")]);
+
+        // A redelivered manifest that is already being ingested is only acked
+        let redelivered = FileEvent {
+            ack_token: AckToken::new("redelivered"),
+            ..FileEvent::new(Path::from("out/a/_manifest.json"), "recording")
+        };
+        events.events = RecordingFileEvents::from_events(vec![redelivered]);
+        assert!(events.next_file().await?.is_empty());
+        assert_eq!(events.inner().acked, vec![Path::from("out/a/_manifest.json")]);
+
+        // The manifest is only acked once all of its files are
+        events.ack(&files[0]).await?;
+        assert_eq!(events.inner().acked.len(), 1);
+        events.ack(&files[1]).await?;
+        assert_eq!(events.inner().acked.last(), Some(&Path::from("out/a/_manifest.json")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_unverified() -> Result<()> {
+        let storage = Arc::new(InMemory::new());
+        storage.put(&Path::from("out/a/part-0.parquet"), "0123456789".into()).await?;
+        storage.put(&Path::from("out/a/_manifest.json"), r#"[{"path": "part-0.parquet", "size": 5, "md5": "abc"}]"#.into()).await?;
+        let mut events = ManifestFileEvents::new(
+            recording(&["out/a/_manifest.json"]),
+            storage,
+            ManifestFileEventsOptions {
+                verify_sizes: false,
+                ..Default::default()
+            },
+        )?;
+
+        // Nothing is left on the events for the processor to check either
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].size, None);
+        assert_eq!(files[0].e_tag, None);
+        Ok(())
+    }
+}
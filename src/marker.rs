@@ -0,0 +1,440 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use object_store::path::Path;
+use object_store::DynObjectStore;
+use tokio::time::Instant;
+use tracing::{debug, error, warn};
+
+use crate::manifest::parse_manifest;
+use crate::{AckToken, FileEvent, FileEventKind, FileEvents};
+
+#[derive(Debug, Clone)]
+pub struct MarkerFileEventsOptions {
+    /// File written once a directory is complete.
+    pub marker_name: String,
+    /// File listing the parts a directory should contain, see [parse_manifest]. The directory is
+    /// complete once all of them have arrived, with or without a marker.
+    pub manifest_name: Option<String>,
+    /// Directories still waiting on their marker after this long are logged as overdue, once.
+    pub timeout: Duration,
+}
+
+impl Default for MarkerFileEventsOptions {
+    fn default() -> Self {
+        Self {
+            marker_name: String::from("_SUCCESS"),
+            manifest_name: None,
+            timeout: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// The parent directory of `path`, empty for files at the root.
+fn directory(path: &Path) -> String {
+    path.as_ref().rsplit_once('/').map_or("", |(dir, _)| dir).to_string()
+}
+
+/// Whether `dir` is `prefix` or somewhere below it, e.g. a `year=2023` partition of a job's output.
+fn is_under(dir: &str, prefix: &str) -> bool {
+    prefix.is_empty() || dir == prefix || dir.strip_prefix(prefix).map_or(false, |rest| rest.starts_with('/'))
+}
+
+/// Files starting with `_` or `.` are bookkeeping rather than data, e.g. `_SUCCESS` or Hadoop's `.crc` files.
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('_') || name.starts_with('.')
+}
+
+/// A directory we have seen files for but that isn't complete yet.
+struct PendingDirectory {
+    files: Vec<FileEvent>,
+    // The marker and manifest events, acked along with the last of the directory's files
+    markers: Vec<FileEvent>,
+    // Set once a marker or manifest arrives, the parts this directory and those below it contain
+    expected: Option<HashSet<String>>,
+    first_seen: Instant,
+    overdue: bool,
+}
+
+impl PendingDirectory {
+    fn new() -> Self {
+        Self {
+            files: vec![],
+            markers: vec![],
+            expected: None,
+            first_seen: Instant::now(),
+            overdue: false,
+        }
+    }
+}
+
+/// A directory handed out as one group, waiting on the processor.
+struct ReleasedDirectory {
+    outstanding: usize,
+    markers: Vec<FileEvent>,
+    failed: Option<anyhow::Error>,
+}
+
+/// Holds back created files until their directory is complete, then hands them all out as one
+/// group so they are committed in a single transaction. A directory is complete once its marker
+/// file, e.g. `_SUCCESS`, and every part stored in it when the marker arrived have, or every part
+/// listed in its manifest has. Notifications are unordered, so the marker can't be trusted to come
+/// last. Partitions below the directory, e.g. `out/year=2023` for `out/_SUCCESS`, are part of it.
+///
+/// Held files stay in flight with the wrapped source, so it needs to keep them there long enough,
+/// e.g. with an SQS visibility heartbeat.
+pub struct MarkerFileEvents<F: FileEvents> {
+    events: F,
+    storage: Arc<DynObjectStore>,
+    opts: MarkerFileEventsOptions,
+    pending: HashMap<String, PendingDirectory>,
+    released: HashMap<String, ReleasedDirectory>,
+}
+
+impl<F: FileEvents> MarkerFileEvents<F> {
+    /// `storage` is used to list marked directories and read manifests.
+    pub fn new(events: F, storage: Arc<DynObjectStore>, opts: MarkerFileEventsOptions) -> Self {
+        Self {
+            events,
+            storage,
+            opts,
+            pending: HashMap::new(),
+            released: HashMap::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.events
+    }
+
+    /// Directories that have been waiting on their marker for longer than the timeout.
+    pub fn overdue(&self) -> Vec<&str> {
+        let mut overdue = self
+            .pending
+            .iter()
+            .filter(|(_, dir)| dir.first_seen.elapsed() >= self.opts.timeout)
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        overdue.sort();
+        overdue
+    }
+
+    /// The parts stored under `dir` right now, leaving out hidden files such as the marker.
+    async fn list_parts(&self, dir: &str) -> Result<HashSet<String>> {
+        let prefix = Path::from(dir);
+        let objects = self.storage.list((!dir.is_empty()).then_some(&prefix)).await?.try_collect::<Vec<_>>().await?;
+        Ok(objects
+            .into_iter()
+            .filter(|meta| !meta.location.filename().map_or(true, is_hidden))
+            .filter(|meta| meta.location.filename() != self.opts.manifest_name.as_deref())
+            .map(|meta| meta.location.to_string())
+            .collect())
+    }
+
+    async fn read_manifest(&self, dir: &str, manifest: &Path) -> Result<HashSet<String>> {
+        let bytes = self.storage.get(manifest).await?.bytes().await?;
+        Ok(parse_manifest(dir, &bytes)?.into_iter().map(|entry| entry.path).collect())
+    }
+
+    /// Whether a held file still has `token`, sources such as SQS share one token between every
+    /// file in a message and redeliver with the same one.
+    fn held_token(&self, token: &AckToken) -> bool {
+        self.pending.values().flat_map(|pending| &pending.files).any(|file| &file.ack_token == token)
+    }
+
+    /// Adds `event` to its directory, returns the events to hand out straight away.
+    async fn hold(&mut self, event: FileEvent) -> Result<Option<FileEvent>> {
+        if event.kind != FileEventKind::Created {
+            return Ok(Some(event));
+        }
+        let dir = directory(&event.path);
+        let name = event.path.filename().unwrap_or_default().to_string();
+        let expected = if name == self.opts.marker_name {
+            Some(self.list_parts(&dir).await?)
+        } else if Some(&name) == self.opts.manifest_name.as_ref() {
+            Some(self.read_manifest(&dir, &event.path).await?)
+        } else {
+            None
+        };
+        if let Some(expected) = expected {
+            let pending = self.pending.entry(dir).or_insert_with(PendingDirectory::new);
+            pending.expected.get_or_insert_with(HashSet::new).extend(expected);
+            pending.markers.push(event);
+        } else {
+            let pending = self.pending.entry(dir).or_insert_with(PendingDirectory::new);
+            // A file held past the source's visibility timeout is delivered again, only the latest
+            // delivery is kept and the one it replaces is settled so the source can let go of it
+            let superseded = match pending.files.iter_mut().find(|file| file.path == event.path) {
+                Some(held) => Some(std::mem::replace(held, event)),
+                None => {
+                    pending.files.push(event);
+                    None
+                }
+            };
+            if let Some(superseded) = superseded.filter(|held| !self.held_token(&held.ack_token)) {
+                self.events.ack(&superseded).await?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether every part `dir` expects has arrived, in it or a directory below it.
+    fn is_complete(&self, dir: &str) -> bool {
+        let Some(expected) = self.pending.get(dir).and_then(|pending| pending.expected.as_ref()) else {
+            return false;
+        };
+        let arrived = self
+            .pending
+            .iter()
+            .filter(|(other, _)| is_under(other, dir))
+            .flat_map(|(_, pending)| pending.files.iter().map(|file| file.path.to_string()))
+            .collect::<HashSet<_>>();
+        expected.is_subset(&arrived)
+    }
+
+    /// Hands out every complete directory as a group, along with the directories below it.
+    async fn release(&mut self) -> Result<Vec<FileEvent>> {
+        let mut complete = self.pending.keys().filter(|dir| self.is_complete(dir)).cloned().collect::<Vec<_>>();
+        // Outermost first, a complete directory takes any complete ones below it along
+        complete.sort();
+
+        let mut files = vec![];
+        for dir in complete {
+            let mut below = self.pending.keys().filter(|other| is_under(other, &dir)).cloned().collect::<Vec<_>>();
+            if below.is_empty() {
+                continue;
+            }
+            below.sort();
+            let mut parts = vec![];
+            let mut markers = vec![];
+            for other in below {
+                if let Some(pending) = self.pending.remove(&other) {
+                    parts.extend(pending.files);
+                    markers.extend(pending.markers);
+                }
+            }
+            if parts.is_empty() {
+                debug!("Directory {} is complete but has no files to ingest", dir);
+                for marker in &markers {
+                    self.events.ack(marker).await?;
+                }
+                continue;
+            }
+            self.released.insert(
+                dir.clone(),
+                ReleasedDirectory {
+                    outstanding: parts.len(),
+                    markers,
+                    failed: None,
+                },
+            );
+            files.extend(parts.into_iter().map(|file| FileEvent {
+                group: Some(dir.clone()),
+                ..file
+            }));
+        }
+
+        for (dir, pending) in self.pending.iter_mut() {
+            if !pending.overdue && pending.first_seen.elapsed() >= self.opts.timeout {
+                pending.overdue = true;
+                warn!(
+                    "Directory {} has been waiting {:?} for {} with {} files held",
+                    dir,
+                    pending.first_seen.elapsed(),
+                    self.opts.marker_name,
+                    pending.files.len()
+                );
+            }
+        }
+        Ok(files)
+    }
+
+    /// Settles one file of a released directory, the markers follow once all of them are.
+    async fn settle(&mut self, event: &FileEvent, error: Option<&anyhow::Error>) -> Result<()> {
+        let Some(group) = &event.group else {
+            return Ok(());
+        };
+        let Some(released) = self.released.get_mut(group) else {
+            return Ok(());
+        };
+        if let (None, Some(error)) = (&released.failed, error) {
+            released.failed = Some(anyhow::anyhow!("{} failed: {:#}", event.path, error));
+        }
+        released.outstanding = released.outstanding.saturating_sub(1);
+        if released.outstanding > 0 {
+            return Ok(());
+        }
+
+        let Some(ReleasedDirectory { markers, failed, .. }) = self.released.remove(group) else {
+            return Ok(());
+        };
+        for marker in &markers {
+            match &failed {
+                // The markers come back with the files, so the directory can complete again
+                Some(err) => self.events.nack(marker, err).await?,
+                None => self.events.ack(marker).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: FileEvents> FileEvents for MarkerFileEvents<F> {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut files = vec![];
+        for event in self.events.next_file().await? {
+            match self.hold(event.clone()).await {
+                Ok(Some(event)) => files.push(event),
+                Ok(None) => {}
+                Err(err) => {
+                    error!("Unable to read manifest {}: {:?}", event.path, err);
+                    self.events.nack(&event, &err).await?;
+                }
+            }
+        }
+        files.extend(self.release().await?);
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        self.events.ack(event).await?;
+        self.settle(event, None).await
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        self.events.nack(event, error).await?;
+        self.settle(event, Some(error)).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use object_store::memory::InMemory;
+    use object_store::ObjectStore;
+
+    use crate::test_utils::RecordingFileEvents;
+
+    use super::*;
+
+    fn recording(paths: &[&str]) -> RecordingFileEvents {
+        RecordingFileEvents::new(paths.iter().map(|path| Path::from(*path)).collect())
+    }
+
+    /// Storage holding `paths`, as the job wrote them.
+    async fn stored(paths: &[&str]) -> Result<Arc<InMemory>> {
+        let storage = Arc::new(InMemory::new());
+        for path in paths {
+            storage.put(&Path::from(*path), "0123456789".into()).await?;
+        }
+        Ok(storage)
+    }
+
+    #[tokio::test]
+    pub async fn test_marker() -> Result<()> {
+        let paths = ["out/a/part-0.parquet", "out/b/part-0.parquet", "out/a/part-1.parquet", "out/a/_SUCCESS", "out/c/_SUCCESS"];
+        let mut events = MarkerFileEvents::new(
+            recording(&paths),
+            stored(&paths).await?,
+            MarkerFileEventsOptions {
+                timeout: Duration::ZERO,
+                ..Default::default()
+            },
+        );
+        let files = events.next_file().await?;
+        let paths = files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["out/a/part-0.parquet", "out/a/part-1.parquet"]);
+        assert!(files.iter().all(|file| file.group.as_deref() == Some("out/a")));
+        // A marker with nothing to ingest is acked straight away
+        assert_eq!(events.inner().acked, vec![Path::from("out/c/_SUCCESS")]);
+        assert_eq!(events.overdue(), vec!["out/b"]);
+
+        events.ack(&files[0]).await?;
+        assert_eq!(events.inner().acked.len(), 2);
+        events.ack(&files[1]).await?;
+        assert_eq!(events.inner().acked.last(), Some(&Path::from("out/a/_SUCCESS")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_marker_before_parts() -> Result<()> {
+        let storage = stored(&["out/a/part-0.parquet", "out/a/part-1.parquet", "out/a/_SUCCESS"]).await?;
+        let mut events = MarkerFileEvents::new(recording(&["out/a/part-0.parquet", "out/a/_SUCCESS"]), storage, MarkerFileEventsOptions::default());
+        assert!(events.next_file().await?.is_empty());
+
+        // The marker came first, the directory waits for the part it listed
+        events.events = recording(&["out/a/part-1.parquet"]);
+        let files = events.next_file().await?;
+        let paths = files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["out/a/part-0.parquet", "out/a/part-1.parquet"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_partitions() -> Result<()> {
+        let paths = ["out/year=2023/part-0.parquet", "out/year=2024/part-0.parquet", "out/_SUCCESS", "other/part-0.parquet"];
+        let mut events = MarkerFileEvents::new(recording(&paths), stored(&paths).await?, MarkerFileEventsOptions::default());
+
+        // Both partitions are committed with the marker above them
+        let files = events.next_file().await?;
+        let paths = files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["out/year=2023/part-0.parquet", "out/year=2024/part-0.parquet"]);
+        assert!(files.iter().all(|file| file.group.as_deref() == Some("out")));
+
+        events.ack(&files[0]).await?;
+        events.ack(&files[1]).await?;
+        assert_eq!(events.inner().acked.last(), Some(&Path::from("out/_SUCCESS")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_redelivered() -> Result<()> {
+        let delivery = |token: &str| FileEvent {
+            ack_token: AckToken::new(token),
+            ..FileEvent::new(Path::from("out/a/part-0.parquet"), "test")
+        };
+        let mut events = MarkerFileEvents::new(
+            RecordingFileEvents::from_events(vec![delivery("first")]),
+            Arc::new(InMemory::new()),
+            MarkerFileEventsOptions::default(),
+        );
+        assert!(events.next_file().await?.is_empty());
+
+        // The held file comes back before the marker, it is still ingested once
+        events.events = RecordingFileEvents::from_events(vec![delivery("second"), FileEvent::new(Path::from("out/a/_SUCCESS"), "test")]);
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].ack_token.as_str(), "second");
+        // The first delivery is settled with the source as soon as it is replaced
+        assert_eq!(events.inner().acked, vec![Path::from("out/a/part-0.parquet")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_manifest() -> Result<()> {
+        let storage = Arc::new(InMemory::new());
+        storage.put(&Path::from("out/a/_manifest"), "part-0.parquet\npart-1.parquet\n".into()).await?;
+        let mut events = MarkerFileEvents::new(
+            recording(&["out/a/_manifest", "out/a/part-0.parquet"]),
+            storage,
+            MarkerFileEventsOptions {
+                manifest_name: Some("_manifest".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(events.next_file().await?.is_empty());
+
+        // The last listed part completes the directory
+        events.events = recording(&["out/a/part-1.parquet"]);
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 2);
+
+        // A failed part puts the manifest back too, so the directory can complete again
+        events.nack(&files[0], &anyhow::anyhow!("failed")).await?;
+        events.ack(&files[1]).await?;
+        assert_eq!(events.inner().nacked, vec![Path::from("out/a/part-0.parquet"), Path::from("out/a/_manifest")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::future::{select_all, LocalBoxFuture};
+use object_store::path::Path;
+use tokio::time::{timeout_at, Instant};
+use tracing::error;
+
+use crate::{AckToken, FileEvent, FileEvents};
+
+/// [FileEvents] as a trait object, so sources of different types can be merged.
+pub trait DynFileEvents {
+    fn next_file(&mut self) -> LocalBoxFuture<'_, Result<Vec<FileEvent>>>;
+    fn ack<'a>(&'a mut self, event: &'a FileEvent) -> LocalBoxFuture<'a, Result<()>>;
+    fn nack<'a>(&'a mut self, event: &'a FileEvent, error: &'a anyhow::Error) -> LocalBoxFuture<'a, Result<()>>;
+}
+
+impl<F: FileEvents> DynFileEvents for F {
+    fn next_file(&mut self) -> LocalBoxFuture<'_, Result<Vec<FileEvent>>> {
+        Box::pin(FileEvents::next_file(self))
+    }
+
+    fn ack<'a>(&'a mut self, event: &'a FileEvent) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(FileEvents::ack(self, event))
+    }
+
+    fn nack<'a>(&'a mut self, event: &'a FileEvent, error: &'a anyhow::Error) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(FileEvents::nack(self, event, error))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergedFileEventsOptions {
+    /// Longest a poll waits when no source has files. Sources still polling then carry on in the
+    /// background and their files are handed out by a later poll, nothing is cancelled.
+    pub poll_timeout: Duration,
+    /// Once one source has files, how long the others get to catch up before they are handed out.
+    pub grace_period: Duration,
+    /// Files one source contributes to a poll, the rest wait for the next one.
+    pub max_files_per_source: usize,
+    /// How long a failing source is left alone, doubling with each failure in a row.
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+}
+
+impl Default for MergedFileEventsOptions {
+    fn default() -> Self {
+        Self {
+            poll_timeout: Duration::from_secs(30),
+            grace_period: Duration::from_millis(50),
+            max_files_per_source: 1000,
+            retry_backoff: Duration::from_secs(1),
+            max_retry_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A source's poll, which owns the source until it finishes.
+type Poll = LocalBoxFuture<'static, (Box<dyn DynFileEvents>, Result<Vec<FileEvent>>)>;
+
+struct Source {
+    label: String,
+    // Taken by the poll while one is running
+    events: Option<Box<dyn DynFileEvents>>,
+    poll: Option<Poll>,
+    // Files already polled but not yet handed out, the source isn't polled again until it's empty
+    backlog: VecDeque<FileEvent>,
+    // The `source` each handed out file had before it was relabelled. The source isn't polled
+    // again until all of them are settled, so their acks never wait on a poll that might not end.
+    origins: HashMap<(AckToken, Path), String>,
+    // Acks and nacks that arrived while the source was polling anyway, e.g. repeated ones
+    deferred: Vec<(FileEvent, Option<anyhow::Error>)>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Source {
+    fn backoff(&self, opts: &MergedFileEventsOptions) -> Duration {
+        let doublings = self.failures.saturating_sub(1).min(16);
+        (opts.retry_backoff * 2u32.pow(doublings)).min(opts.max_retry_backoff)
+    }
+
+    /// Starts polling if the source is idle, has nothing waiting or unsettled and isn't backing off.
+    fn start(&mut self, now: Instant) {
+        if !self.backlog.is_empty() || !self.origins.is_empty() || self.retry_at.map_or(false, |at| at > now) {
+            return;
+        }
+        let Some(mut events) = self.events.take() else {
+            return;
+        };
+        self.poll = Some(Box::pin(async move {
+            let files = events.next_file().await;
+            (events, files)
+        }));
+    }
+
+    async fn settle(events: &mut Box<dyn DynFileEvents>, event: &FileEvent, error: Option<&anyhow::Error>) -> Result<()> {
+        match error {
+            Some(error) => events.nack(event, error).await,
+            None => events.ack(event).await,
+        }
+    }
+
+    /// Takes the source back from a finished poll.
+    async fn finish(&mut self, idx: usize, mut events: Box<dyn DynFileEvents>, files: Result<Vec<FileEvent>>, opts: &MergedFileEventsOptions) {
+        self.poll = None;
+        for (event, error) in std::mem::take(&mut self.deferred) {
+            if let Err(err) = Self::settle(&mut events, &event, error.as_ref()).await {
+                error!("Failed to settle {} with {}: {:?}", event.path, self.label, err);
+            }
+        }
+        self.events = Some(events);
+
+        match files {
+            Ok(files) => {
+                self.failures = 0;
+                self.retry_at = None;
+                for file in files {
+                    let ack_token = AckToken::new(format!("{}:{}", idx, file.ack_token.as_str()));
+                    self.origins.insert((ack_token.clone(), file.path.clone()), file.source.clone());
+                    self.backlog.push_back(FileEvent {
+                        source: self.label.clone(),
+                        ack_token,
+                        ..file
+                    });
+                }
+            }
+            Err(err) => {
+                self.failures += 1;
+                let backoff = self.backoff(opts);
+                self.retry_at = Some(Instant::now() + backoff);
+                error!("Failed to poll {}, {} failures in a row, retrying in {:?}: {:?}", self.label, self.failures, backoff, err);
+            }
+        }
+    }
+}
+
+/// Feeds one processor from several sources. Sources are polled concurrently and their files
+/// interleaved, each file's `source` is replaced with the label of the source it came from and
+/// acks and nacks are routed back to it. A poll returns as soon as any source has files, sources
+/// that are still polling carry on and are picked up by later polls. A source with files handed
+/// out isn't polled again until they are all acked or nacked.
+pub struct MergedFileEvents {
+    sources: Vec<Source>,
+    opts: MergedFileEventsOptions,
+    // Rotates which source goes first, so none of them is always at the front of a batch
+    next_start: usize,
+}
+
+impl MergedFileEvents {
+    pub fn new(opts: MergedFileEventsOptions) -> Self {
+        Self {
+            sources: vec![],
+            opts,
+            next_start: 0,
+        }
+    }
+
+    pub fn add<F: FileEvents + 'static>(&mut self, label: impl Into<String>, events: F) -> Result<()> {
+        let label = label.into();
+        if self.sources.iter().any(|source| source.label == label) {
+            return Err(anyhow!("There is already a source labelled {}", label));
+        }
+        self.sources.push(Source {
+            label,
+            events: Some(Box::new(events)),
+            poll: None,
+            backlog: VecDeque::new(),
+            origins: HashMap::new(),
+            deferred: vec![],
+            failures: 0,
+            retry_at: None,
+        });
+        Ok(())
+    }
+
+    /// The source an event came from, and the event as that source handed it out.
+    fn route(&mut self, event: &FileEvent) -> Result<(&mut Source, FileEvent)> {
+        let (idx, token) = event
+            .ack_token
+            .as_str()
+            .split_once(':')
+            .and_then(|(idx, token)| Some((idx.parse::<usize>().ok()?, token)))
+            .ok_or_else(|| anyhow!("{} was not handed out by a merged source", event.path))?;
+        let source = self
+            .sources
+            .get_mut(idx)
+            .ok_or_else(|| anyhow!("{} was not handed out by a merged source", event.path))?;
+        let origin = source
+            .origins
+            .remove(&(event.ack_token.clone(), event.path.clone()))
+            .unwrap_or_else(|| event.source.clone());
+        let original = FileEvent {
+            source: origin,
+            ack_token: AckToken::new(token),
+            ..event.clone()
+        };
+        Ok((source, original))
+    }
+
+    /// Acks or nacks `event` with its source, or once the source's poll finishes if it is polling.
+    async fn settle(&mut self, event: &FileEvent, error: Option<&anyhow::Error>) -> Result<()> {
+        let (source, original) = self.route(event)?;
+        match &mut source.events {
+            Some(events) => Source::settle(events, &original, error).await,
+            None => {
+                source.deferred.push((original, error.map(|error| anyhow!("{:#}", error))));
+                Ok(())
+            }
+        }
+    }
+
+    /// Takes files from each backlog in turn, up to the per source limit.
+    fn interleave(&mut self) -> Vec<FileEvent> {
+        let count = self.sources.len();
+        let mut files = vec![];
+        let mut taken = vec![0; count];
+        loop {
+            let mut any = false;
+            for offset in 0..count {
+                let idx = (self.next_start + offset) % count;
+                if taken[idx] >= self.opts.max_files_per_source {
+                    continue;
+                }
+                if let Some(file) = self.sources[idx].backlog.pop_front() {
+                    files.push(file);
+                    taken[idx] += 1;
+                    any = true;
+                }
+            }
+            if !any {
+                break;
+            }
+        }
+        self.next_start = (self.next_start + 1) % count.max(1);
+        files
+    }
+
+    fn has_files(&self) -> bool {
+        self.sources.iter().any(|source| !source.backlog.is_empty())
+    }
+}
+
+impl FileEvents for MergedFileEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let now = Instant::now();
+        for source in self.sources.iter_mut() {
+            source.start(now);
+        }
+
+        let mut deadline = now + if self.has_files() { self.opts.grace_period } else { self.opts.poll_timeout };
+        loop {
+            let (idxs, polls): (Vec<usize>, Vec<&mut Poll>) = self
+                .sources
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(idx, source)| Some((idx, source.poll.as_mut()?)))
+                .unzip();
+            if polls.is_empty() {
+                break;
+            }
+            let finished = timeout_at(deadline, select_all(polls))
+                .await
+                .ok()
+                .map(|(output, idx, _)| (idxs[idx], output));
+            let Some((idx, (events, files))) = finished else {
+                break;
+            };
+            self.sources[idx].finish(idx, events, files, &self.opts).await;
+            if self.has_files() {
+                deadline = deadline.min(Instant::now() + self.opts.grace_period);
+            }
+        }
+        Ok(self.interleave())
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        self.settle(event, None).await
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        self.settle(event, Some(error)).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// Hands out its files once, sharing what was acked so it can be checked after being merged.
+    struct SharedFileEvents {
+        files: Vec<FileEvent>,
+        acked: Rc<RefCell<Vec<FileEvent>>>,
+    }
+
+    impl SharedFileEvents {
+        fn new(paths: &[&str], acked: Rc<RefCell<Vec<FileEvent>>>) -> Self {
+            let files = paths
+                .iter()
+                .enumerate()
+                .map(|(idx, path)| FileEvent {
+                    ack_token: AckToken::new(idx.to_string()),
+                    ..FileEvent::new(Path::from(*path), "shared")
+                })
+                .collect();
+            Self { files, acked }
+        }
+    }
+
+    impl FileEvents for SharedFileEvents {
+        async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+            Ok(std::mem::take(&mut self.files))
+        }
+
+        async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+            self.acked.borrow_mut().push(event.clone());
+            Ok(())
+        }
+    }
+
+    struct FailingFileEvents;
+
+    impl FileEvents for FailingFileEvents {
+        async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+            Err(anyhow!("unreachable"))
+        }
+    }
+
+    /// Hands out its file, if it has one, then long polls without ever finding another.
+    struct SlowFileEvents(Option<&'static str>);
+
+    impl FileEvents for SlowFileEvents {
+        async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+            if let Some(path) = self.0.take() {
+                return Ok(vec![FileEvent::new(Path::from(path), "slow")]);
+            }
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(vec![])
+        }
+    }
+
+    /// Hands out its file, then polls forever.
+    struct BlockingFileEvents {
+        file: Option<FileEvent>,
+        acked: Rc<RefCell<Vec<FileEvent>>>,
+    }
+
+    impl FileEvents for BlockingFileEvents {
+        async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+            if let Some(file) = self.file.take() {
+                return Ok(vec![file]);
+            }
+            std::future::pending().await
+        }
+
+        async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+            self.acked.borrow_mut().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_merge() -> Result<()> {
+        let queue_acked = Rc::new(RefCell::new(vec![]));
+        let listing_acked = Rc::new(RefCell::new(vec![]));
+        let mut events = MergedFileEvents::new(MergedFileEventsOptions {
+            poll_timeout: Duration::from_secs(10),
+            max_files_per_source: 2,
+            ..Default::default()
+        });
+        events.add("queue", SharedFileEvents::new(&["q/1", "q/2", "q/3"], queue_acked.clone()))?;
+        events.add("listing", SharedFileEvents::new(&["l/1"], listing_acked.clone()))?;
+        events.add("broken", FailingFileEvents)?;
+        events.add("slow", SlowFileEvents(None))?;
+        events.add("idle", SlowFileEvents(Some("i/1")))?;
+        assert!(events.add("queue", FailingFileEvents).is_err());
+
+        // Neither the failing nor the slow source holds up the others
+        let started = Instant::now();
+        let files = events.next_file().await?;
+        let paths = files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["q/1", "l/1", "i/1", "q/2"]);
+        assert_eq!(files[1].source, "listing");
+
+        // The rest of the queue's files come on the next poll, without waiting on the idle sources
+        let more = events.next_file().await?;
+        assert_eq!(more.len(), 1);
+        assert_eq!(more[0].path.as_ref(), "q/3");
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // The slow poll wasn't cancelled, it carries on in the background, the idle source isn't
+        // polled again until its file is settled
+        assert!(events.sources[3].poll.is_some());
+        assert!(events.sources[4].poll.is_none());
+
+        events.ack(&files[1]).await?;
+        events.ack(&more[0]).await?;
+        assert_eq!(listing_acked.borrow()[0].ack_token.as_str(), "0");
+        assert_eq!(listing_acked.borrow()[0].source, "shared");
+        assert_eq!(queue_acked.borrow()[0].ack_token.as_str(), "2");
+        assert!(events.ack(&FileEvent::new(Path::from("other"), "other")).await.is_err());
+
+        // A source that is polling gets repeated acks once the poll finishes
+        events.ack(&files[2]).await?;
+        assert!(events.sources[4].deferred.is_empty());
+        events.sources[4].start(Instant::now());
+        assert!(events.sources[4].poll.is_some());
+        events.ack(&files[2]).await?;
+        assert_eq!(events.sources[4].deferred.len(), 1);
+        assert_eq!(events.sources[4].deferred[0].0.source, "slow");
+
+        let broken = &events.sources[2];
+        assert_eq!(broken.failures, 1);
+        assert!(broken.retry_at.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_blocking_source() -> Result<()> {
+        let acked = Rc::new(RefCell::new(vec![]));
+        let mut events = MergedFileEvents::new(MergedFileEventsOptions {
+            poll_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        events.add(
+            "blocking",
+            BlockingFileEvents {
+                file: Some(FileEvent::new(Path::from("b/1"), "blocking")),
+                acked: acked.clone(),
+            },
+        )?;
+
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+
+        // Not polled again while its file is out, so the ack reaches it even though the next poll
+        // would never finish
+        assert!(events.next_file().await?.is_empty());
+        assert!(events.sources[0].poll.is_none());
+        events.ack(&files[0]).await?;
+        assert_eq!(acked.borrow().len(), 1);
+        assert_eq!(acked.borrow()[0].source, "blocking");
+
+        assert!(events.next_file().await?.is_empty());
+        assert!(events.sources[0].poll.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::consumer::pull::Config;
+use async_nats::jetstream::consumer::{AckPolicy, PullConsumer};
+use async_nats::jetstream::{AckKind, Message};
+use futures::StreamExt;
+use tracing::{debug, error, warn};
+
+use crate::in_flight::InFlight;
+use crate::payload::{parse_payload, PayloadFormat};
+use crate::{AckToken, FileEvent, FileEvents};
+
+#[derive(Debug, Clone)]
+pub struct JetStreamEventsOptions {
+    pub url: String,
+    pub stream: String,
+    /// Name of the durable pull consumer, created if it doesn't exist.
+    pub durable_name: String,
+    /// Only subjects matching one of these are delivered, wildcards allowed, e.g.
+    /// `uploads.*.parquet`. Everything in the stream is delivered when empty. More than one needs
+    /// nats-server 2.10 or later.
+    pub filter_subjects: Vec<String>,
+    pub payload: PayloadFormat,
+    pub max_messages: usize,
+    /// How long a fetch waits for messages.
+    pub wait_time: Duration,
+    /// How long the server waits for an ack before redelivering.
+    pub ack_wait: Duration,
+    /// Deliveries after which a failing message is terminated instead of redelivered.
+    pub max_deliver: i64,
+    /// How long a nacked message waits before it is redelivered.
+    pub nak_delay: Duration,
+}
+
+impl Default for JetStreamEventsOptions {
+    fn default() -> Self {
+        Self {
+            url: String::from("nats://127.0.0.1:4222"),
+            stream: String::new(),
+            durable_name: String::from("delta-file-ingest"),
+            filter_subjects: vec![],
+            payload: PayloadFormat::Path,
+            max_messages: 100,
+            wait_time: Duration::from_secs(10),
+            ack_wait: Duration::from_secs(300),
+            max_deliver: 5,
+            nak_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The durable pull consumer for `opts`.
+fn consumer_config(opts: &JetStreamEventsOptions) -> Config {
+    // A single filter goes in the old field so older servers still understand it
+    let (filter_subject, filter_subjects) = match opts.filter_subjects.as_slice() {
+        [subject] => (subject.clone(), vec![]),
+        subjects => (String::new(), subjects.to_vec()),
+    };
+    Config {
+        durable_name: Some(opts.durable_name.clone()),
+        filter_subject,
+        filter_subjects,
+        ack_policy: AckPolicy::Explicit,
+        ack_wait: opts.ack_wait,
+        max_deliver: opts.max_deliver,
+        ..Default::default()
+    }
+}
+
+/// How to hand back a message that failed after `delivered` deliveries, terminated once the
+/// server won't deliver it again anyway.
+fn nack_reply(opts: &JetStreamEventsOptions, delivered: i64) -> AckKind {
+    if delivered >= opts.max_deliver {
+        return AckKind::Term;
+    }
+    AckKind::Nak(Some(opts.nak_delay))
+}
+
+/// Reads "file uploaded" messages through a durable JetStream pull consumer with explicit acks.
+pub struct JetStreamEvents {
+    consumer: PullConsumer,
+    opts: JetStreamEventsOptions,
+    in_flight: InFlight<Message>,
+}
+
+impl JetStreamEvents {
+    pub async fn new(opts: JetStreamEventsOptions) -> Result<Self> {
+        let client = async_nats::connect(&opts.url).await?;
+        let stream = async_nats::jetstream::new(client)
+            .get_stream(&opts.stream)
+            .await
+            .map_err(|err| anyhow!("Unable to get stream {}: {}", opts.stream, err))?;
+        let config = consumer_config(&opts);
+        let consumer = stream
+            .get_or_create_consumer(&opts.durable_name, config)
+            .await
+            .map_err(|err| anyhow!("Unable to create consumer {}: {}", opts.durable_name, err))?;
+
+        Ok(Self {
+            consumer,
+            opts,
+            in_flight: InFlight::new(),
+        })
+    }
+
+    async fn reply(message: &Message, kind: AckKind) -> Result<()> {
+        message.ack_with(kind).await.map_err(|err| anyhow!("Unable to ack message: {}", err))
+    }
+}
+
+impl FileEvents for JetStreamEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut messages = self
+            .consumer
+            .batch()
+            .max_messages(self.opts.max_messages)
+            .expires(self.opts.wait_time)
+            .messages()
+            .await
+            .map_err(|err| anyhow!("Unable to fetch messages: {}", err))?;
+
+        let mut files = vec![];
+        while let Some(message) = messages.next().await {
+            let message = message.map_err(|err| anyhow!("Unable to fetch messages: {}", err))?;
+            let info = message.info().map_err(|err| anyhow!("Message has no JetStream metadata: {}", err))?;
+            let token = AckToken::new(info.stream_sequence.to_string());
+            let events = match parse_payload(&self.opts.payload, &message.payload, &self.opts.stream, &token) {
+                Ok(events) => events,
+                Err(err) => {
+                    // Redelivering won't make it parse
+                    error!("Unable to parse message {} on {}: {:?}", token.as_str(), message.subject, err);
+                    Self::reply(&message, AckKind::Term).await?;
+                    continue;
+                }
+            };
+            if events.is_empty() {
+                debug!("Message {} has no files to ingest, acking it", token.as_str());
+                Self::reply(&message, AckKind::Ack).await?;
+                continue;
+            }
+            self.in_flight.insert(token.as_str(), message, events.len());
+            files.extend(events);
+        }
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        let Some(message) = self.in_flight.ack(event.ack_token.as_str()) else {
+            return Ok(());
+        };
+        // Double ack so we know the server has it before the next poll, not just sent it
+        message.double_ack().await.map_err(|err| anyhow!("Unable to ack message: {}", err))
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        // As with SQS the whole message is redelivered, acks for its other files then find nothing in flight
+        let Some(message) = self.in_flight.remove(event.ack_token.as_str()) else {
+            return Ok(());
+        };
+        let delivered = message.info().map(|info| info.delivered).unwrap_or_default();
+        let reply = nack_reply(&self.opts, delivered);
+        if matches!(reply, AckKind::Term) {
+            warn!("Giving up on message {} after {} deliveries: {:#}", event.ack_token.as_str(), delivered, error);
+        }
+        Self::reply(&message, reply).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use async_nats::jetstream::stream;
+
+    use super::*;
+
+    #[test]
+    pub fn test_consumer_config() {
+        let opts = JetStreamEventsOptions {
+            filter_subjects: vec!["uploads.*.parquet".to_string()],
+            ..Default::default()
+        };
+        let config = consumer_config(&opts);
+        assert_eq!(config.filter_subject, "uploads.*.parquet");
+        assert!(config.filter_subjects.is_empty());
+        assert_eq!(config.ack_policy, AckPolicy::Explicit);
+
+        let opts = JetStreamEventsOptions {
+            filter_subjects: vec!["uploads.*.parquet".to_string(), "uploads.*.csv".to_string()],
+            ..Default::default()
+        };
+        let config = consumer_config(&opts);
+        assert!(config.filter_subject.is_empty());
+        assert_eq!(config.filter_subjects, opts.filter_subjects);
+
+        let config = consumer_config(&JetStreamEventsOptions::default());
+        assert!(config.filter_subject.is_empty() && config.filter_subjects.is_empty());
+    }
+
+    #[test]
+    pub fn test_nack_reply() {
+        let opts = JetStreamEventsOptions {
+            max_deliver: 3,
+            ..Default::default()
+        };
+        assert!(matches!(nack_reply(&opts, 1), AckKind::Nak(Some(delay)) if delay == opts.nak_delay));
+        assert!(matches!(nack_reply(&opts, 3), AckKind::Term));
+    }
+
+    /// Runs against a local server, start one with `nats-server -js`, then run
+    /// `NATS_URL=nats://127.0.0.1:4222 cargo test --features nats test_jetstream -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    pub async fn test_jetstream() -> Result<()> {
+        let url = std::env::var("NATS_URL")?;
+        let jetstream = async_nats::jetstream::new(async_nats::connect(&url).await?);
+        let _ = jetstream.delete_stream("UPLOADS").await;
+        jetstream
+            .create_stream(stream::Config {
+                name: "UPLOADS".to_string(),
+                subjects: vec!["uploads.>".to_string()],
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
+        for (subject, path) in [("uploads.logs", "bucket/a.log"), ("uploads.parquet", "bucket/a.parquet"), ("uploads.csv", "bucket/a.csv")] {
+            jetstream
+                .publish(subject.to_string(), path.into())
+                .await
+                .map_err(|err| anyhow!("{}", err))?
+                .await
+                .map_err(|err| anyhow!("{}", err))?;
+        }
+
+        let mut events = JetStreamEvents::new(JetStreamEventsOptions {
+            url,
+            stream: "UPLOADS".to_string(),
+            filter_subjects: vec!["uploads.parquet".to_string(), "uploads.csv".to_string()],
+            wait_time: Duration::from_millis(500),
+            max_deliver: 2,
+            nak_delay: Duration::ZERO,
+            ..Default::default()
+        })
+        .await?;
+        let files = events.next_file().await?;
+        let paths = files.iter().map(|file| file.path.as_ref()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["bucket/a.parquet", "bucket/a.csv"]);
+        events.ack(&files[1]).await?;
+
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        assert_eq!(retried[0].path, files[0].path);
+        // Out of deliveries, so it is terminated rather than sent again
+        events.nack(&retried[0], &anyhow!("failed again")).await?;
+        assert!(events.next_file().await?.is_empty());
+        Ok(())
+    }
+}
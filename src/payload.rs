@@ -0,0 +1,111 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use object_store::path::Path;
+
+use crate::aws::model::{Notification, ObjectNotification};
+use crate::{AckToken, FileEvent};
+
+/// How a message from a queue or stream names the file it is about.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+    /// An S3 event notification, bare or in an SNS or EventBridge envelope.
+    #[default]
+    S3Event,
+    /// The payload is the path itself.
+    Path,
+    /// A JSON document with the path as a string at this pointer, e.g. `/data/path`.
+    JsonPointer(String),
+}
+
+impl FromStr for PayloadFormat {
+    type Err = anyhow::Error;
+
+    /// Parses `s3`, `path` or `pointer:/some/field`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "s3" => Ok(Self::S3Event),
+            "path" => Ok(Self::Path),
+            _ => match s.strip_prefix("pointer:") {
+                Some(pointer) if pointer.starts_with('/') => Ok(Self::JsonPointer(pointer.to_string())),
+                _ => Err(anyhow!("Unknown payload format {}, expected s3, path or pointer:/field", s)),
+            },
+        }
+    }
+}
+
+/// Parses a path or url into an object path, `s3://bucket/key` becomes `bucket/key`.
+pub fn parse_path(path: &str) -> Result<Path> {
+    let path = path.trim();
+    let path = path.split_once("://").map_or(path, |(_, rest)| rest);
+    if path.trim_matches('/').is_empty() {
+        return Err(anyhow!("Empty path in payload"));
+    }
+    Ok(Path::parse(path.trim_matches('/'))?)
+}
+
+/// Turns a payload into the file events it describes, all sharing `ack_token`.
+pub fn parse_payload(format: &PayloadFormat, payload: &[u8], source: &str, ack_token: &AckToken) -> Result<Vec<FileEvent>> {
+    let payload = std::str::from_utf8(payload)?;
+    match format {
+        PayloadFormat::S3Event => Notification::parse(payload, false)?
+            .objects()
+            .into_iter()
+            .filter(ObjectNotification::is_object_change)
+            .map(|object| object.into_file_event(source, ack_token.clone()))
+            .collect(),
+        PayloadFormat::Path => {
+            let path = parse_path(payload)?;
+            Ok(vec![FileEvent {
+                ack_token: ack_token.clone(),
+                ..FileEvent::new(path, source)
+            }])
+        }
+        PayloadFormat::JsonPointer(pointer) => {
+            let value: serde_json::Value = serde_json::from_str(payload)?;
+            let path = value
+                .pointer(pointer)
+                .and_then(|path| path.as_str())
+                .ok_or_else(|| anyhow!("No string at {} in payload", pointer))?;
+            Ok(vec![FileEvent {
+                ack_token: ack_token.clone(),
+                ..FileEvent::new(parse_path(path)?, source)
+            }])
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_formats() -> Result<()> {
+        assert_eq!("s3".parse::<PayloadFormat>()?, PayloadFormat::S3Event);
+        assert_eq!("path".parse::<PayloadFormat>()?, PayloadFormat::Path);
+        assert_eq!("pointer:/data/path".parse::<PayloadFormat>()?, PayloadFormat::JsonPointer("/data/path".to_string()));
+        assert!("pointer:data".parse::<PayloadFormat>().is_err());
+        assert!("xml".parse::<PayloadFormat>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_parse_payload() -> Result<()> {
+        let token = AckToken::new("1");
+        let s3 = std::fs::read("./test_files/s3_object_created.json")?;
+        let events = parse_payload(&PayloadFormat::S3Event, &s3, "test", &token)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path.as_ref(), "landing-bucket/year=2023/month=01/part 0000=a.parquet");
+        assert_eq!(events[0].ack_token, token);
+
+        let events = parse_payload(&PayloadFormat::Path, b"s3://landing-bucket/a/b.parquet\n", "test", &token)?;
+        assert_eq!(events[0].path.as_ref(), "landing-bucket/a/b.parquet");
+
+        let pointer = PayloadFormat::JsonPointer("/file/path".to_string());
+        let events = parse_payload(&pointer, br#"{"file": {"path": "/data/c.parquet"}}"#, "test", &token)?;
+        assert_eq!(events[0].path.as_ref(), "data/c.parquet");
+        assert!(parse_payload(&pointer, br#"{"file": {}}"#, "test", &token).is_err());
+        assert!(parse_payload(&PayloadFormat::Path, b"  ", "test", &token).is_err());
+        Ok(())
+    }
+}
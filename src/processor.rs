@@ -1,10 +1,12 @@
+use std::collections::HashMap;
 use std::io::Cursor;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
+use chrono::Utc;
 use deltalake::{DeltaDataTypeVersion, DeltaTable};
-use deltalake::action::{Action, DeltaOperation, SaveMode};
+use deltalake::action::{Action, DeltaOperation, Remove, SaveMode};
 use deltalake::arrow::{csv, json};
 use deltalake::arrow::error::Result as ArrowResult;
 use deltalake::arrow::record_batch::RecordBatch;
@@ -12,14 +14,50 @@ use deltalake::parquet::arrow::ProjectionMask;
 use deltalake::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
 use deltalake::writer::{DeltaWriter, RecordBatchWriter};
 use futures::{Stream, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
 use object_store::{DynObjectStore, ObjectStore};
-use object_store::path::Path;
 use tokio_util::compat::*;
+use tracing::error;
 
-use crate::FileEvents;
+use crate::{FileEvent, FileEventKind, FileEvents};
+
+/// Tag on every add action naming the source object its rows were read from.
+pub const SOURCE_PATH_TAG: &str = "sourcePath";
+/// Tags recording which version of the source object was read, when the source knew it.
+pub const SOURCE_VERSION_TAG: &str = "sourceVersion";
+pub const SOURCE_ETAG_TAG: &str = "sourceETag";
+/// Tag naming the group a file was committed with, see [FileEvent::group].
+pub const SOURCE_GROUP_TAG: &str = "sourceGroup";
+
+/// Checks the bytes we read are the ones the event was about. Sizes are always compared, with
+/// `check_etag` single part ETags are compared against the MD5 of the content as well.
+pub fn verify_object(file: &FileEvent, bytes: &[u8], check_etag: bool) -> Result<()> {
+    if let Some(size) = file.size {
+        if size != bytes.len() {
+            return Err(anyhow!("{} is {} bytes but the event says {}, it changed since the event", file.path, bytes.len(), size));
+        }
+    }
+
+    let e_tag = file.e_tag.as_deref().map(|e_tag| e_tag.trim_matches('"'));
+    match e_tag {
+        // Multipart ETags are a hash of part hashes, we can't check those without the part sizes
+        Some(e_tag) if check_etag && !e_tag.contains('-') => {
+            let digest = format!("{:x}", Md5::digest(bytes));
+            if !digest.eq_ignore_ascii_case(e_tag) {
+                return Err(anyhow!("{} has MD5 {} but the event ETag is {}, it changed since the event", file.path, digest, e_tag));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
 
 pub struct EventProcessorOptions {
     pub poll_time: u64,
+    /// Remove the table files that came from a source object once that object is deleted.
+    pub mirror_removals: bool,
+    /// Reject objects whose content doesn't match the event's ETag, see [verify_object].
+    pub verify_etag: bool,
 }
 
 pub struct EventProcessor<F>
@@ -43,7 +81,6 @@ impl<F> EventProcessor<F>
         opts: EventProcessorOptions,
     ) -> Result<Self> {
         let storage = Arc::new(storage);
-        dbg!(table.schema());
         Ok(Self {
             events,
             storage,
@@ -52,26 +89,46 @@ impl<F> EventProcessor<F>
         })
     }
 
-    async fn write_file(&mut self, file: Path) -> Result<DeltaDataTypeVersion> {
-        let partition_cols = {
-            let metadata = self.table.get_metadata()?;
-            metadata.partition_columns.clone()
-        };
-        let obj_stream = self.storage.get(&file).await?;
-        let stream = self.create_parquet_reader(obj_stream.bytes().await?).await?;
+    /// Reads `file` into new table files, returning their add actions and the tags put on them.
+    async fn file_actions(&mut self, file: &FileEvent) -> Result<(Vec<Action>, HashMap<String, Option<String>>)> {
+        // object_store can't read a specific version, so the best we can do is notice the object
+        // was replaced since the event and refuse to ingest it
+        let bytes = self.storage.get(&file.path).await?.bytes().await?;
+        verify_object(file, &bytes, self.opts.verify_etag)?;
+        let stream = self.create_parquet_reader(bytes).await?;
 
         let mut batch_writer = RecordBatchWriter::for_table(&self.table)?;
         for batch in stream {
             let batch = batch?;
             batch_writer.write(batch).await?;
         }
+        let mut source_tags = HashMap::from([(SOURCE_PATH_TAG.to_string(), Some(file.path.to_string()))]);
+        if let Some(version) = &file.version {
+            source_tags.insert(SOURCE_VERSION_TAG.to_string(), Some(version.clone()));
+        }
+        if let Some(e_tag) = &file.e_tag {
+            source_tags.insert(SOURCE_ETAG_TAG.to_string(), Some(e_tag.clone()));
+        }
+        if let Some(group) = &file.group {
+            source_tags.insert(SOURCE_GROUP_TAG.to_string(), Some(group.clone()));
+        }
         let actions: Vec<Action> = batch_writer
             .flush()
             .await?
             .into_iter()
-            .map(Action::add)
+            .map(|mut add| {
+                add.tags.get_or_insert_with(HashMap::new).extend(source_tags.clone());
+                Action::add(add)
+            })
             .collect();
+        Ok((actions, source_tags))
+    }
 
+    async fn commit_write(&mut self, actions: Vec<Action>, app_metadata: serde_json::Map<String, serde_json::Value>) -> Result<DeltaDataTypeVersion> {
+        let partition_cols = {
+            let metadata = self.table.get_metadata()?;
+            metadata.partition_columns.clone()
+        };
         let mut tx = self.table.create_transaction(None);
         tx.add_actions(actions);
 
@@ -80,17 +137,138 @@ impl<F> EventProcessor<F>
             partition_by: Some(partition_cols),
             predicate: None,
         };
-        tx.commit(Some(app), None).await.map_err(Into::into)
+        tx.commit(Some(app), Some(app_metadata)).await.map_err(Into::into)
+    }
+
+    async fn write_file(&mut self, file: &FileEvent) -> Result<DeltaDataTypeVersion> {
+        let (actions, source_tags) = self.file_actions(file).await?;
+        let app_metadata = source_tags
+            .into_iter()
+            .filter_map(|(k, v)| Some((k, serde_json::Value::String(v?))))
+            .collect();
+        self.commit_write(actions, app_metadata).await
+    }
+
+    /// Writes every file of a group in one transaction, nothing is committed if any of them fails.
+    async fn write_group(&mut self, group: &str, files: &[FileEvent]) -> Result<DeltaDataTypeVersion> {
+        let mut actions = vec![];
+        for file in files {
+            let (file_actions, _) = self
+                .file_actions(file)
+                .await
+                .map_err(|err| err.context(format!("Failed to read {} of group {}", file.path, group)))?;
+            actions.extend(file_actions);
+        }
+        let source_paths = files
+            .iter()
+            .map(|file| serde_json::Value::String(file.path.to_string()))
+            .collect();
+        let app_metadata = serde_json::Map::from_iter([
+            (SOURCE_GROUP_TAG.to_string(), serde_json::Value::String(group.to_string())),
+            (SOURCE_PATH_TAG.to_string(), serde_json::Value::Array(source_paths)),
+        ]);
+        self.commit_write(actions, app_metadata).await
+    }
+
+    /// Removes every table file that was written from `file`, returns `None` if there were none.
+    async fn remove_file(&mut self, file: &FileEvent) -> Result<Option<DeltaDataTypeVersion>> {
+        self.table.update().await?;
+        let source_path = file.path.to_string();
+        let deletion_timestamp = Utc::now().timestamp_millis();
+        let actions: Vec<Action> = self
+            .table
+            .get_state()
+            .files()
+            .iter()
+            .filter(|add| {
+                add.tags
+                    .as_ref()
+                    .and_then(|tags| tags.get(SOURCE_PATH_TAG))
+                    .map_or(false, |tag| tag.as_deref() == Some(source_path.as_str()))
+            })
+            .map(|add| {
+                Action::remove(Remove {
+                    path: add.path.clone(),
+                    deletion_timestamp: Some(deletion_timestamp),
+                    data_change: true,
+                    extended_file_metadata: Some(true),
+                    partition_values: Some(add.partition_values.clone()),
+                    size: Some(add.size),
+                    tags: add.tags.clone(),
+                })
+            })
+            .collect();
+        if actions.is_empty() {
+            return Ok(None);
+        }
+
+        let mut tx = self.table.create_transaction(None);
+        tx.add_actions(actions);
+        let app = DeltaOperation::Delete { predicate: None };
+        Ok(Some(tx.commit(Some(app), None).await?))
+    }
+
+    async fn process(&mut self, file: &FileEvent) -> Result<()> {
+        match file.kind {
+            FileEventKind::Created => self.write_file(file).await.map(|_| ()),
+            FileEventKind::Removed if self.opts.mirror_removals => self.remove_file(file).await.map(|_| ()),
+            FileEventKind::Removed => Ok(()),
+        }
     }
 
     pub async fn run(&mut self) -> Result<()> {
+        let mut groups: Vec<(String, Vec<FileEvent>)> = vec![];
+        let mut unsettled = 0;
         for file in self.events.next_file().await? {
-            self.write_file(file).await?;
+            if let (Some(group), FileEventKind::Created) = (&file.group, file.kind) {
+                match groups.iter_mut().find(|(name, _)| name == group) {
+                    Some((_, files)) => files.push(file),
+                    None => groups.push((group.clone(), vec![file])),
+                }
+                continue;
+            }
+            let outcome = self.process(&file).await;
+            if let Err(err) = &outcome {
+                // One bad file shouldn't hold up the rest, the source decides when to give up on it
+                error!("Failed to ingest {}: {:?}", file.path, err);
+            }
+            if !self.settle(&file, &outcome).await {
+                unsettled += 1;
+            }
+        }
+
+        for (group, files) in groups {
+            let outcome = self.write_group(&group, &files).await.map(|_| ());
+            if let Err(err) = &outcome {
+                error!("Failed to ingest group {}: {:?}", group, err);
+            }
+            for file in &files {
+                if !self.settle(file, &outcome).await {
+                    unsettled += 1;
+                }
+            }
+        }
+        if unsettled > 0 {
+            return Err(anyhow!("Unable to ack or nack {} files", unsettled));
         }
-        dbg!(self.table.get_state());
         Ok(())
     }
 
+    /// Acks or nacks `file` depending on `outcome`. Failing to do so is logged rather than
+    /// returned, the rest of the batch has been taken from the source too and still needs settling.
+    async fn settle(&mut self, file: &FileEvent, outcome: &Result<()>) -> bool {
+        let settled = match outcome {
+            Ok(_) => self.events.ack(file).await,
+            Err(err) => self.events.nack(file, err).await,
+        };
+        match settled {
+            Ok(_) => true,
+            Err(err) => {
+                error!("Unable to settle {} with its source: {:?}", file.path, err);
+                false
+            }
+        }
+    }
 
     async fn create_parquet_reader(&self, bytes: Bytes) -> Result<impl Iterator<Item=ArrowResult<RecordBatch>>> {
         let mask = ProjectionMask::all();
@@ -126,7 +304,7 @@ pub mod test {
     use object_store::local::LocalFileSystem;
     use object_store::path::Path;
 
-    use crate::test_utils::{create_initialized_table, StaticFileEvents};
+    use crate::test_utils::{create_initialized_table, RecordingFileEvents, StaticFileEvents};
 
     use super::*;
 
@@ -145,10 +323,172 @@ pub mod test {
             LocalFileSystem::new(),
             table,
             EventProcessorOptions {
-                poll_time: 20
+                poll_time: 20,
+                mirror_removals: false,
+                verify_etag: false,
             },
         )?;
 
         processor.run().await
     }
+
+    #[tokio::test]
+    pub async fn test_processor_acks() -> Result<()> {
+        let table = create_initialized_table(&[]).await?;
+        let good_file = Path::from_filesystem_path("./test_files/alltypes_tiny_pages.parquet")?;
+        let bad_file = Path::from_filesystem_path("./test_files/uc_schema.json")?;
+
+        let events = RecordingFileEvents::new(vec![
+            good_file.clone(),
+            bad_file.clone(),
+            good_file.clone(),
+        ]);
+        let mut processor = EventProcessor::new(
+            events,
+            LocalFileSystem::new(),
+            table,
+            EventProcessorOptions {
+                poll_time: 20,
+                mirror_removals: false,
+                verify_etag: false,
+            },
+        )?;
+
+        processor.run().await?;
+        assert_eq!(processor.events.acked, vec![good_file.clone(), good_file]);
+        assert_eq!(processor.events.nacked, vec![bad_file]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_processor_failed_ack() -> Result<()> {
+        let table = create_initialized_table(&[]).await?;
+        let good_file = Path::from_filesystem_path("./test_files/alltypes_tiny_pages.parquet")?;
+        let bad_file = Path::from_filesystem_path("./test_files/uc_schema.json")?;
+
+        let mut events = RecordingFileEvents::new(vec![good_file.clone(), bad_file.clone(), good_file.clone()]);
+        events.failing_acks = 1;
+        let mut processor = EventProcessor::new(
+            events,
+            LocalFileSystem::new(),
+            table,
+            EventProcessorOptions {
+                poll_time: 20,
+                mirror_removals: false,
+                verify_etag: false,
+            },
+        )?;
+
+        // The failed ack is reported, but only once the rest of the batch has been settled
+        assert!(processor.run().await.is_err());
+        assert_eq!(processor.events.acked, vec![good_file]);
+        assert_eq!(processor.events.nacked, vec![bad_file]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_processor_removals() -> Result<()> {
+        let table = create_initialized_table(&[]).await?;
+        let test_file = Path::from_filesystem_path("./test_files/alltypes_tiny_pages.parquet")?;
+        let other_file = Path::from_filesystem_path("./test_files/alltypes_plain.snappy.parquet")?;
+
+        let events = RecordingFileEvents::from_events(vec![
+            FileEvent::new(test_file.clone(), "test"),
+            FileEvent::new(other_file.clone(), "test"),
+            FileEvent {
+                kind: FileEventKind::Removed,
+                ..FileEvent::new(test_file.clone(), "test")
+            },
+        ]);
+        let mut processor = EventProcessor::new(
+            events,
+            LocalFileSystem::new(),
+            table,
+            EventProcessorOptions {
+                poll_time: 20,
+                mirror_removals: true,
+                verify_etag: false,
+            },
+        )?;
+
+        processor.run().await?;
+        assert_eq!(processor.events.acked.len(), 3);
+        let remaining = processor.table.get_state().files();
+        assert!(!remaining.is_empty());
+        for add in remaining {
+            let tags = add.tags.as_ref().unwrap();
+            assert_eq!(tags.get(SOURCE_PATH_TAG), Some(&Some(other_file.to_string())));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_processor_groups() -> Result<()> {
+        let table = create_initialized_table(&[]).await?;
+        let test_file = Path::from_filesystem_path("./test_files/alltypes_tiny_pages.parquet")?;
+        let other_file = Path::from_filesystem_path("./test_files/alltypes_plain.snappy.parquet")?;
+        let bad_file = Path::from_filesystem_path("./test_files/uc_schema.json")?;
+        let grouped = |path: &Path, group: &str| FileEvent {
+            group: Some(group.to_string()),
+            ..FileEvent::new(path.clone(), "test")
+        };
+
+        let events = RecordingFileEvents::from_events(vec![
+            grouped(&test_file, "complete"),
+            grouped(&test_file, "broken"),
+            grouped(&other_file, "complete"),
+            grouped(&bad_file, "broken"),
+        ]);
+        let mut processor = EventProcessor::new(
+            events,
+            LocalFileSystem::new(),
+            table,
+            EventProcessorOptions {
+                poll_time: 20,
+                mirror_removals: false,
+                verify_etag: false,
+            },
+        )?;
+
+        processor.run().await?;
+        assert_eq!(processor.events.acked, vec![test_file.clone(), other_file.clone()]);
+        assert_eq!(processor.events.nacked, vec![test_file.clone(), bad_file]);
+        // Both files of the complete group landed in a single commit, none of the broken one did
+        assert_eq!(processor.table.version(), 1);
+        let files = processor.table.get_state().files();
+        assert!(!files.is_empty());
+        for add in files {
+            let tags = add.tags.as_ref().unwrap();
+            assert_eq!(tags.get(SOURCE_GROUP_TAG), Some(&Some("complete".to_string())));
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_verify_object() -> Result<()> {
+        let bytes = std::fs::read("./test_files/alltypes_tiny_pages.parquet")?;
+        let path = Path::from("bucket/alltypes_tiny_pages.parquet");
+        let event = FileEvent {
+            size: Some(454233),
+            e_tag: Some("\"8357501945fd8b633ef677b095a7e635\"".to_string()),
+            ..FileEvent::new(path.clone(), "test")
+        };
+        verify_object(&event, &bytes, true)?;
+
+        let wrong_size = FileEvent { size: Some(1024), ..event.clone() };
+        assert!(verify_object(&wrong_size, &bytes, false).is_err());
+
+        let wrong_e_tag = FileEvent {
+            e_tag: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+            ..event.clone()
+        };
+        assert!(verify_object(&wrong_e_tag, &bytes, true).is_err());
+        verify_object(&wrong_e_tag, &bytes, false)?;
+
+        let multipart = FileEvent {
+            e_tag: Some("d41d8cd98f00b204e9800998ecf8427e-3".to_string()),
+            ..event
+        };
+        verify_object(&multipart, &bytes, true)
+    }
 }
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use redis::aio::MultiplexedConnection;
+use redis::{cmd, from_redis_value, RedisError, Value};
+use tracing::{debug, error, warn};
+
+use crate::in_flight::InFlight;
+use crate::payload::{parse_payload, PayloadFormat};
+use crate::{AckToken, FileEvent, FileEvents};
+
+pub const ERROR_FIELD: &str = "error";
+
+#[derive(Debug, Clone)]
+pub struct RedisStreamEventsOptions {
+    pub url: String,
+    pub stream: String,
+    pub group: String,
+    /// Should be stable across restarts, so our own pending entries are picked up again.
+    pub consumer: String,
+    /// Entry field holding the payload.
+    pub field: String,
+    pub payload: PayloadFormat,
+    /// Where a newly created group starts reading, `0` for the whole stream or `$` for new entries only.
+    pub start_id: String,
+    pub max_entries: usize,
+    /// How long a read blocks waiting for entries.
+    pub block_time: Duration,
+    /// Entries pending this long, on any consumer, are claimed by us. Failed entries are retried
+    /// this way too.
+    pub min_idle_time: Duration,
+    /// Deliveries after which a failing entry is moved to `dead_letter_stream`.
+    pub max_deliveries: Option<u32>,
+    pub dead_letter_stream: Option<String>,
+}
+
+impl Default for RedisStreamEventsOptions {
+    fn default() -> Self {
+        Self {
+            url: String::from("redis://127.0.0.1/"),
+            stream: String::new(),
+            group: String::from("delta-file-ingest"),
+            consumer: String::from("delta-file-ingest"),
+            field: String::from("path"),
+            payload: PayloadFormat::Path,
+            start_id: String::from("0"),
+            max_entries: 100,
+            block_time: Duration::from_secs(10),
+            min_idle_time: Duration::from_secs(300),
+            max_deliveries: None,
+            dead_letter_stream: None,
+        }
+    }
+}
+
+type Entry = (String, HashMap<String, String>);
+
+/// Reads `[id, [field, value, ...]]` pairs, skipping the nils left by entries deleted while pending.
+fn parse_entries(value: &Value) -> Result<Vec<Entry>> {
+    let Value::Bulk(items) = value else {
+        return Ok(vec![]);
+    };
+    items
+        .iter()
+        .filter(|item| !matches!(item, Value::Nil))
+        .map(|item| match item {
+            Value::Bulk(entry) if matches!(entry.get(1), Some(Value::Nil)) => Ok((from_redis_value(&entry[0])?, HashMap::new())),
+            _ => Ok(from_redis_value(item)?),
+        })
+        .collect()
+}
+
+/// Reads file paths producers `XADD` to a stream, through a consumer group.
+pub struct RedisStreamEvents {
+    connection: MultiplexedConnection,
+    opts: RedisStreamEventsOptions,
+    // Our own pending entries are read back once after a restart, before any new ones
+    recovered: bool,
+    claim_cursor: String,
+    // Fields of each delivered entry, kept to dead letter it
+    in_flight: InFlight<HashMap<String, String>>,
+}
+
+impl RedisStreamEvents {
+    pub async fn new(opts: RedisStreamEventsOptions) -> Result<Self> {
+        if opts.max_deliveries.is_some() && opts.dead_letter_stream.is_none() {
+            return Err(anyhow!("max_deliveries needs a dead_letter_stream to send entries to"));
+        }
+        let mut connection = redis::Client::open(opts.url.as_str())?.get_multiplexed_tokio_connection().await?;
+        let created: Result<(), RedisError> = cmd("XGROUP")
+            .arg("CREATE")
+            .arg(&opts.stream)
+            .arg(&opts.group)
+            .arg(&opts.start_id)
+            .arg("MKSTREAM")
+            .query_async(&mut connection)
+            .await;
+        match created {
+            Err(err) if err.code() != Some("BUSYGROUP") => return Err(err.into()),
+            _ => {}
+        }
+
+        Ok(Self {
+            connection,
+            opts,
+            recovered: false,
+            claim_cursor: String::from("0-0"),
+            in_flight: InFlight::new(),
+        })
+    }
+
+    async fn read(&mut self, id: &str, block: bool) -> Result<Vec<Entry>> {
+        let mut read = cmd("XREADGROUP");
+        read.arg("GROUP").arg(&self.opts.group).arg(&self.opts.consumer).arg("COUNT").arg(self.opts.max_entries);
+        if block {
+            read.arg("BLOCK").arg(self.opts.block_time.as_millis() as u64);
+        }
+        let reply: Option<Vec<(String, Value)>> = read.arg("STREAMS").arg(&self.opts.stream).arg(id).query_async(&mut self.connection).await?;
+        let mut entries = vec![];
+        for (_, value) in reply.into_iter().flatten() {
+            entries.extend(parse_entries(&value)?);
+        }
+        Ok(entries)
+    }
+
+    /// Takes over entries that have been pending too long, whether a dead consumer left them or
+    /// they were nacked. Walks the pending list a page per poll.
+    async fn claim(&mut self) -> Result<Vec<Entry>> {
+        let reply: Value = cmd("XAUTOCLAIM")
+            .arg(&self.opts.stream)
+            .arg(&self.opts.group)
+            .arg(&self.opts.consumer)
+            .arg(self.opts.min_idle_time.as_millis() as u64)
+            .arg(&self.claim_cursor)
+            .arg("COUNT")
+            .arg(self.opts.max_entries)
+            .query_async(&mut self.connection)
+            .await?;
+        let Value::Bulk(items) = reply else {
+            return Err(anyhow!("Unexpected XAUTOCLAIM reply {:?}", reply));
+        };
+        self.claim_cursor = items.first().map(from_redis_value).transpose()?.unwrap_or_else(|| String::from("0-0"));
+        let claimed = items.get(1).map(parse_entries).transpose()?.unwrap_or_default();
+        Ok(claimed.into_iter().filter(|(id, _)| !self.in_flight.contains(id)).collect())
+    }
+
+    async fn xack(&mut self, id: &str) -> Result<()> {
+        cmd("XACK").arg(&self.opts.stream).arg(&self.opts.group).arg(id).query_async::<_, ()>(&mut self.connection).await?;
+        Ok(())
+    }
+
+    async fn deliveries(&mut self, id: &str) -> Result<u32> {
+        let pending: Vec<(String, String, u64, u32)> = cmd("XPENDING")
+            .arg(&self.opts.stream)
+            .arg(&self.opts.group)
+            .arg(id)
+            .arg(id)
+            .arg(1)
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(pending.first().map_or(0, |(_, _, _, deliveries)| *deliveries))
+    }
+
+    /// Copies the entry to the dead letter stream along with why it failed, then acks it.
+    async fn dead_letter(&mut self, id: &str, fields: &HashMap<String, String>, reason: &str) -> Result<()> {
+        let Some(dead_letter_stream) = self.opts.dead_letter_stream.clone() else {
+            return Ok(());
+        };
+        warn!("Dead lettering stream entry {}: {}", id, reason);
+        let mut add = cmd("XADD");
+        add.arg(&dead_letter_stream).arg("*");
+        for (field, value) in fields.iter().filter(|(field, _)| field.as_str() != ERROR_FIELD) {
+            add.arg(field).arg(value);
+        }
+        add.arg(ERROR_FIELD).arg(reason).query_async::<_, ()>(&mut self.connection).await?;
+        self.xack(id).await
+    }
+
+    fn parse_entry(&self, id: &str, fields: &HashMap<String, String>) -> Result<Vec<FileEvent>> {
+        let payload = fields
+            .get(&self.opts.field)
+            .ok_or_else(|| anyhow!("Entry {} has no {} field", id, self.opts.field))?;
+        parse_payload(&self.opts.payload, payload.as_bytes(), &self.opts.stream, &AckToken::new(id))
+    }
+}
+
+impl FileEvents for RedisStreamEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        let mut entries = vec![];
+        if !self.recovered {
+            entries.extend(self.read("0", false).await?);
+            self.recovered = true;
+        }
+        entries.extend(self.claim().await?);
+        if entries.is_empty() {
+            entries.extend(self.read(">", true).await?);
+        }
+
+        let mut files = vec![];
+        for (id, fields) in entries {
+            let events = match self.parse_entry(&id, &fields) {
+                Ok(events) => events,
+                Err(err) => {
+                    // Retrying won't help, so don't leave it for XAUTOCLAIM to hand out forever
+                    error!("Unable to parse stream entry {}: {:?}", id, err);
+                    match self.opts.max_deliveries {
+                        Some(_) => self.dead_letter(&id, &fields, &format!("{:#}", err)).await?,
+                        None => self.xack(&id).await?,
+                    }
+                    continue;
+                }
+            };
+            if events.is_empty() {
+                debug!("Stream entry {} has no files to ingest, acking it", id);
+                self.xack(&id).await?;
+                continue;
+            }
+            self.in_flight.insert(id, fields, events.len());
+            files.extend(events);
+        }
+        Ok(files)
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        let id = event.ack_token.as_str();
+        if self.in_flight.ack(id).is_none() {
+            return Ok(());
+        }
+        self.xack(id).await
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        // The entry stays pending, XAUTOCLAIM hands it out again once it has been idle long enough
+        let id = event.ack_token.as_str();
+        let Some(fields) = self.in_flight.remove(id) else {
+            return Ok(());
+        };
+        let Some(max_deliveries) = self.opts.max_deliveries else {
+            return Ok(());
+        };
+        if self.deliveries(id).await? >= max_deliveries {
+            let reason = format!("Failed to ingest {}: {:#}", event.path, error);
+            self.dead_letter(id, &fields, &reason).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn encode(value: &Value) -> Vec<u8> {
+        match value {
+            Value::Nil => b"$-1\r\n".to_vec(),
+            Value::Int(int) => format!(":{}\r\n", int).into_bytes(),
+            Value::Data(data) => [format!("${}\r\n", data.len()).as_bytes(), data, b"\r\n"].concat(),
+            Value::Bulk(items) => {
+                let mut encoded = format!("*{}\r\n", items.len()).into_bytes();
+                items.iter().for_each(|item| encoded.extend(encode(item)));
+                encoded
+            }
+            Value::Status(status) => format!("+{}\r\n", status).into_bytes(),
+            Value::Okay => b"+OK\r\n".to_vec(),
+        }
+    }
+
+    /// Stands in for a Redis server, answering each command with whatever `respond` returns and
+    /// remembering the commands it saw. Returns the url to connect to.
+    async fn fake_redis(mut respond: impl FnMut(&[String]) -> Value + Send + 'static) -> Result<(String, Arc<Mutex<Vec<Vec<String>>>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("redis://{}/", listener.local_addr()?);
+        let commands = Arc::new(Mutex::new(vec![]));
+        let seen = commands.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await? == 0 {
+                    return Ok::<_, anyhow::Error>(());
+                }
+                let mut command = vec![];
+                for _ in 0..line.trim_start_matches('*').trim_end().parse::<usize>()? {
+                    line.clear();
+                    reader.read_line(&mut line).await?;
+                    let mut arg = vec![0; line.trim_start_matches('$').trim_end().parse::<usize>()? + 2];
+                    reader.read_exact(&mut arg).await?;
+                    command.push(String::from_utf8_lossy(&arg[..arg.len() - 2]).to_string());
+                }
+                let reply = respond(&command);
+                seen.lock().unwrap().push(command);
+                writer.write_all(&encode(&reply)).await?;
+            }
+        });
+        Ok((url, commands))
+    }
+
+    fn entry(id: &str, fields: &[(&str, &str)]) -> Value {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let fields = fields.iter().flat_map(|(field, value)| [data(field), data(value)]).collect();
+        Value::Bulk(vec![data(id), Value::Bulk(fields)])
+    }
+
+    #[tokio::test]
+    pub async fn test_ack_and_nack() -> Result<()> {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let mut claims = 0;
+        let (url, commands) = fake_redis(move |command| match command[0].as_str() {
+            // Our own pending entries after a restart, one of them without a payload
+            "XREADGROUP" if command.last().map(String::as_str) == Some("0") => Value::Bulk(vec![Value::Bulk(vec![
+                data("landed"),
+                Value::Bulk(vec![entry("1-0", &[("path", "bucket/a.parquet")]), entry("2-0", &[("other", "x")])]),
+            ])]),
+            "XAUTOCLAIM" => {
+                claims += 1;
+                let claimed = match claims {
+                    1 => vec![],
+                    _ => vec![entry("3-0", &[("path", "bucket/b.parquet")])],
+                };
+                Value::Bulk(vec![data("0-0"), Value::Bulk(claimed), Value::Bulk(vec![])])
+            }
+            "XPENDING" => Value::Bulk(vec![Value::Bulk(vec![data("3-0"), data("delta-file-ingest"), Value::Int(10), Value::Int(2)])]),
+            "XADD" => data("4-0"),
+            "XACK" => Value::Int(1),
+            "XREADGROUP" => Value::Nil,
+            _ => Value::Okay,
+        })
+        .await?;
+        let mut events = RedisStreamEvents::new(RedisStreamEventsOptions {
+            url,
+            stream: "landed".to_string(),
+            max_deliveries: Some(2),
+            dead_letter_stream: Some("landed-dead".to_string()),
+            ..Default::default()
+        })
+        .await?;
+        let sent = |commands: &Arc<Mutex<Vec<Vec<String>>>>| -> Vec<String> {
+            let commands = commands.lock().unwrap();
+            commands.iter().map(|command| format!("{} {}", command[0], command.last().cloned().unwrap_or_default())).collect()
+        };
+
+        // The entry without a payload is dead lettered straight away
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "bucket/a.parquet");
+        assert_eq!(sent(&commands)[3..], ["XADD Entry 2-0 has no path field", "XACK 2-0"]);
+
+        events.ack(&files[0]).await?;
+        events.ack(&files[0]).await?;
+        assert_eq!(sent(&commands)[5..], ["XACK 1-0"]);
+
+        // Out of deliveries, so the nack dead letters it and later acks find nothing in flight
+        let claimed = events.next_file().await?;
+        assert_eq!(claimed[0].path.as_ref(), "bucket/b.parquet");
+        events.nack(&claimed[0], &anyhow!("failed")).await?;
+        events.ack(&claimed[0]).await?;
+        let sent = sent(&commands);
+        assert_eq!(sent[7..], ["XPENDING 1", "XADD Failed to ingest bucket/b.parquet: failed", "XACK 3-0"]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_parse_entries() -> Result<()> {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let value = Value::Bulk(vec![
+            Value::Bulk(vec![data("1-0"), Value::Bulk(vec![data("path"), data("bucket/a.parquet")])]),
+            Value::Nil,
+            Value::Bulk(vec![data("2-0"), Value::Nil]),
+        ]);
+        let entries = parse_entries(&value)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, "1-0");
+        assert_eq!(entries[0].1["path"], "bucket/a.parquet");
+        assert!(entries[1].1.is_empty());
+        assert!(parse_entries(&Value::Nil)?.is_empty());
+        Ok(())
+    }
+
+    /// Runs against a local server, start one with `redis-server`, then run
+    /// `REDIS_URL=redis://127.0.0.1/ cargo test --features redis test_redis -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    pub async fn test_redis() -> Result<()> {
+        let url = std::env::var("REDIS_URL")?;
+        let opts = RedisStreamEventsOptions {
+            url: url.clone(),
+            stream: "landed-test".to_string(),
+            block_time: Duration::from_millis(100),
+            min_idle_time: Duration::ZERO,
+            max_deliveries: Some(2),
+            dead_letter_stream: Some("landed-test-dead".to_string()),
+            ..Default::default()
+        };
+        let mut connection = redis::Client::open(url.as_str())?.get_multiplexed_tokio_connection().await?;
+        cmd("DEL").arg("landed-test").arg("landed-test-dead").query_async::<_, ()>(&mut connection).await?;
+        let mut events = RedisStreamEvents::new(opts).await?;
+        cmd("XADD").arg("landed-test").arg("*").arg("path").arg("bucket/a.parquet").query_async::<_, ()>(&mut connection).await?;
+
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "bucket/a.parquet");
+
+        // Nacked entries are reclaimed, and dead lettered once they run out of deliveries
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        assert_eq!(retried[0].path, files[0].path);
+        events.nack(&retried[0], &anyhow!("failed again")).await?;
+        assert!(events.next_file().await?.is_empty());
+        let dead: usize = cmd("XLEN").arg("landed-test-dead").query_async(&mut connection).await?;
+        assert_eq!(dead, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{AckToken, FileEvent};
+
+/// What became of a nacked file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attempt {
+    /// Queued to be handed out again, after this many failures.
+    Retry(u32),
+    /// Failed this many times, the source has to park it somewhere it won't be lost.
+    Exhausted(u32),
+}
+
+/// Files waiting to be handed out by a source that can't give a single file back to where it came
+/// from, e.g. Kafka, along with how often each has failed.
+///
+/// Sources queue files here as soon as they receive them and only [take](Self::take) at the end of
+/// a poll, so nothing is lost if the poll is dropped part way through.
+#[derive(Debug)]
+pub struct RetryQueue {
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    ready: VecDeque<FileEvent>,
+    // Nacked files waiting out their backoff, with when they are ready again
+    delayed: Vec<(Instant, FileEvent)>,
+    attempts: HashMap<(AckToken, String), u32>,
+}
+
+impl RetryQueue {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            ready: VecDeque::new(),
+            delayed: vec![],
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Holds nacked files back for `backoff` before handing them out again, doubling with every
+    /// failure up to `max_backoff`. Without it they are ready straight away.
+    pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    fn delay(&self, attempts: u32) -> Duration {
+        let doublings = attempts.saturating_sub(1).min(31);
+        self.backoff.saturating_mul(1 << doublings).min(self.max_backoff)
+    }
+
+    pub fn push(&mut self, event: FileEvent) {
+        self.ready.push_back(event);
+    }
+
+    pub fn extend(&mut self, events: impl IntoIterator<Item = FileEvent>) {
+        self.ready.extend(events);
+    }
+
+    /// Whether nothing is ready to be handed out, files still backing off don't count.
+    pub fn is_empty(&self) -> bool {
+        let now = Instant::now();
+        self.ready.is_empty() && self.delayed.iter().all(|(at, _)| *at > now)
+    }
+
+    /// Everything ready to be handed out, oldest first.
+    pub fn take(&mut self) -> Vec<FileEvent> {
+        let now = Instant::now();
+        let (due, delayed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.delayed).into_iter().partition(|(at, _)| *at <= now);
+        self.delayed = delayed;
+        self.ready.extend(due.into_iter().map(|(_, event)| event));
+        self.ready.drain(..).collect()
+    }
+
+    pub fn ack(&mut self, event: &FileEvent) {
+        self.attempts.remove(&(event.ack_token.clone(), event.path.to_string()));
+    }
+
+    /// Counts a failure of `event`, queueing it again unless it is out of attempts.
+    pub fn nack(&mut self, event: &FileEvent) -> Attempt {
+        let key = (event.ack_token.clone(), event.path.to_string());
+        let attempts = self.attempts.entry(key.clone()).or_insert(0);
+        *attempts += 1;
+        let attempts = *attempts;
+        if attempts < self.max_attempts {
+            let delay = self.delay(attempts);
+            if delay.is_zero() {
+                self.ready.push_back(event.clone());
+            } else {
+                self.delayed.push((Instant::now() + delay, event.clone()));
+            }
+            return Attempt::Retry(attempts);
+        }
+        self.attempts.remove(&key);
+        Attempt::Exhausted(attempts)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use object_store::path::Path;
+
+    use super::*;
+
+    #[test]
+    pub fn test_retry_queue() {
+        let mut queue = RetryQueue::new(2);
+        let event = FileEvent {
+            ack_token: AckToken::new("1"),
+            ..FileEvent::new(Path::from("a.parquet"), "test")
+        };
+        queue.push(event.clone());
+        assert_eq!(queue.take().len(), 1);
+        assert!(queue.is_empty());
+
+        assert_eq!(queue.nack(&event), Attempt::Retry(1));
+        assert_eq!(queue.take()[0].path, event.path);
+        assert_eq!(queue.nack(&event), Attempt::Exhausted(2));
+        assert!(queue.is_empty());
+
+        // Attempts start over once a file succeeds
+        queue.nack(&event);
+        queue.ack(&event);
+        assert_eq!(queue.nack(&event), Attempt::Retry(1));
+    }
+
+    #[test]
+    pub fn test_retry_backoff() {
+        let mut queue = RetryQueue::new(3).with_backoff(Duration::from_millis(50), Duration::from_millis(80));
+        let event = FileEvent::new(Path::from("a.parquet"), "test");
+
+        // Held back until its backoff has passed
+        assert_eq!(queue.nack(&event), Attempt::Retry(1));
+        assert!(queue.is_empty());
+        assert!(queue.take().is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!queue.is_empty());
+        assert_eq!(queue.take().len(), 1);
+
+        // The backoff doubles, up to the limit
+        assert_eq!(queue.delay(2), Duration::from_millis(80));
+        assert_eq!(queue.nack(&event), Attempt::Retry(2));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(queue.take().is_empty());
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(queue.take().len(), 1);
+        assert_eq!(queue.nack(&event), Attempt::Exhausted(3));
+    }
+}
@@ -0,0 +1,33 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Loads state saved by [store], or the default if nothing has been saved at `path` yet.
+pub fn load<T>(path: &Path) -> Result<T>
+    where
+        T: DeserializeOwned + Default,
+{
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Saves `state` as JSON, writing to a temporary file first so a crash never leaves half a file.
+pub fn store<T>(path: &Path, state: &T) -> Result<()>
+    where
+        T: Serialize,
+{
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(state)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
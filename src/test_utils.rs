@@ -1,18 +1,146 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use deltalake::{DeltaTable, DeltaTableBuilder, DeltaTableError, DeltaTableMetaData, Schema, SchemaDataType, SchemaField};
 use deltalake::action::Protocol;
 use deltalake::arrow::datatypes::{DataType, Field, TimeUnit};
 use object_store::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
 
-use crate::FileEvents;
+use crate::{FileEvent, FileEvents};
 
 pub struct StaticFileEvents(pub Vec<Path>);
 
 impl FileEvents for StaticFileEvents {
-    async fn next_file(&mut self) -> Result<Vec<Path>> {
-        Ok(self.0.clone())
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        Ok(self.0.iter().cloned().map(|path| FileEvent::new(path, "static")).collect())
+    }
+}
+
+/// Hands out its files once and remembers what the processor did with them.
+pub struct RecordingFileEvents {
+    files: Vec<FileEvent>,
+    pub acked: Vec<Path>,
+    pub nacked: Vec<Path>,
+    /// This many of the next acks fail.
+    pub failing_acks: usize,
+}
+
+impl RecordingFileEvents {
+    pub fn new(files: Vec<Path>) -> Self {
+        let files = files.into_iter().map(|path| FileEvent::new(path, "recording")).collect();
+        Self::from_events(files)
+    }
+
+    pub fn from_events(files: Vec<FileEvent>) -> Self {
+        Self {
+            files,
+            acked: vec![],
+            nacked: vec![],
+            failing_acks: 0,
+        }
+    }
+}
+
+impl FileEvents for RecordingFileEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        Ok(std::mem::take(&mut self.files))
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        if self.failing_acks > 0 {
+            self.failing_acks -= 1;
+            return Err(anyhow!("Unable to ack {}", event.path));
+        }
+        self.acked.push(event.path.clone());
+        Ok(())
+    }
+
+    async fn nack(&mut self, event: &FileEvent, _error: &anyhow::Error) -> Result<()> {
+        self.nacked.push(event.path.clone());
+        Ok(())
+    }
+}
+
+/// A request seen by a [FakeHttpServer].
+#[derive(Debug, Clone)]
+pub struct FakeRequest {
+    pub method: String,
+    /// Path and query string.
+    pub path: String,
+    pub body: String,
+}
+
+/// Stands in for a REST service, answering each request with whatever `respond` returns and
+/// remembering the requests it saw.
+pub struct FakeHttpServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<FakeRequest>>>,
+    task: JoinHandle<()>,
+}
+
+impl FakeHttpServer {
+    pub async fn start(mut respond: impl FnMut(&FakeRequest) -> (u16, String) + Send + 'static) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (mut stream, request) = match Self::read(stream).await {
+                    Ok(read) => read,
+                    Err(err) => {
+                        tracing::warn!("Fake server was unable to read a request: {:?}", err);
+                        continue;
+                    }
+                };
+                let (status, body) = respond(&request);
+                seen.lock().unwrap().push(request);
+                let response = format!("HTTP/1.1 {} Fake\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        Ok(Self { url, requests, task })
+    }
+
+    async fn read(stream: TcpStream) -> Result<(TcpStream, FakeRequest)> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next().ok_or_else(|| anyhow!("Empty request"))?.to_string();
+        let path = parts.next().ok_or_else(|| anyhow!("Request has no path"))?.to_string();
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse()?;
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await?;
+        let body = String::from_utf8(body)?;
+        Ok((reader.into_inner(), FakeRequest { method, path, body }))
+    }
+
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeHttpServer {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, error, warn};
+
+use crate::payload::{parse_payload, PayloadFormat};
+use crate::retry::{Attempt, RetryQueue};
+use crate::{AckToken, FileEvent, FileEvents};
+
+pub const WEBHOOK_SOURCE: &str = "webhook";
+const SPOOL_EXTENSION: &str = "json";
+const DEAD_LETTER_DIR: &str = "dead-letter";
+
+/// How senders prove they are allowed to post events.
+#[derive(Debug, Clone, Default)]
+pub enum WebhookAuth {
+    #[default]
+    None,
+    /// The `Authorization` header must be the secret, with or without a `Bearer ` prefix. This is
+    /// what MinIO sends when a webhook target has an `auth_token`.
+    SharedSecret(String),
+    /// `header` must hold the hex HMAC-SHA256 of the body, optionally prefixed with `sha256=`.
+    HmacSha256 { secret: String, header: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookFileEventsOptions {
+    pub address: SocketAddr,
+    /// Accepted requests are written here before they are acknowledged to the sender, and removed
+    /// once every file in them has been committed.
+    pub spool_dir: PathBuf,
+    pub auth: WebhookAuth,
+    pub max_body_bytes: usize,
+    /// How long a poll waits for a request to arrive.
+    pub poll_timeout: Duration,
+    /// Times a file is tried before its request is moved to the dead letter directory.
+    pub max_attempts: u32,
+    /// Where requests with a file that ran out of attempts are kept, `dead-letter` in the spool
+    /// directory when unset. Posting one again replays all of its files.
+    pub dead_letter_dir: Option<PathBuf>,
+}
+
+impl Default for WebhookFileEventsOptions {
+    fn default() -> Self {
+        Self {
+            address: ([0, 0, 0, 0], 8080).into(),
+            spool_dir: PathBuf::from("spool"),
+            auth: WebhookAuth::default(),
+            max_body_bytes: 1024 * 1024,
+            poll_timeout: Duration::from_secs(10),
+            max_attempts: 5,
+            dead_letter_dir: None,
+        }
+    }
+}
+
+/// Reads either an S3 style notification, as sent by MinIO and most S3 compatible stores, or a
+/// `{"path": "bucket/key"}` document.
+pub fn parse_webhook(body: &[u8], ack_token: &AckToken) -> Result<Vec<FileEvent>> {
+    let value: serde_json::Value = serde_json::from_slice(body)?;
+    let format = match value.get("path") {
+        Some(serde_json::Value::String(_)) => PayloadFormat::JsonPointer("/path".to_string()),
+        _ => PayloadFormat::S3Event,
+    };
+    parse_payload(&format, body, WEBHOOK_SOURCE, ack_token)
+}
+
+/// Compares without bailing out at the first difference, so timing doesn't leak the secret.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len() && left.iter().zip(right).fold(0, |acc, (l, r)| acc | (l ^ r)) == 0
+}
+
+impl WebhookAuth {
+    fn verify(&self, req: &Request<Body>, body: &[u8]) -> bool {
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+        match self {
+            Self::None => true,
+            Self::SharedSecret(secret) => header("authorization")
+                .map(|value| value.strip_prefix("Bearer ").unwrap_or(value))
+                .map_or(false, |value| constant_time_eq(value.as_bytes(), secret.as_bytes())),
+            Self::HmacSha256 { secret, header: name } => {
+                let Some(signature) = header(name) else {
+                    return false;
+                };
+                let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+                let Ok(signature) = hex::decode(signature) else {
+                    return false;
+                };
+                let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+                    return false;
+                };
+                mac.update(body);
+                mac.verify_slice(&signature).is_ok()
+            }
+        }
+    }
+}
+
+/// The server half, shared by every connection.
+struct Receiver {
+    spool_dir: PathBuf,
+    auth: WebhookAuth,
+    max_body_bytes: usize,
+    notify: Arc<Notify>,
+    sequence: AtomicU64,
+}
+
+impl Receiver {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let (status, message) = match self.accept(req).await {
+            Ok(status) => (status, String::new()),
+            Err((status, message)) => {
+                debug!("Rejected webhook request with {}: {}", status, message);
+                (status, message)
+            }
+        };
+        let mut response = Response::new(Body::from(message));
+        *response.status_mut() = status;
+        response
+    }
+
+    async fn accept(&self, mut req: Request<Body>) -> Result<StatusCode, (StatusCode, String)> {
+        if req.method() != Method::POST {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, String::from("Only POST is supported")));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = req.body_mut().data().await {
+            let chunk = chunk.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+            if body.len() + chunk.len() > self.max_body_bytes {
+                return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Body is over {} bytes", self.max_body_bytes)));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        if !self.auth.verify(&req, &body) {
+            return Err((StatusCode::UNAUTHORIZED, String::from("Missing or invalid signature")));
+        }
+        // Reject what we can't read now, while the sender can still do something about it
+        let events = parse_webhook(&body, &AckToken::default())
+            .map_err(|err| (StatusCode::BAD_REQUEST, format!("{:#}", err)))?;
+        if events.is_empty() {
+            return Ok(StatusCode::OK);
+        }
+
+        self.spool(&body).await.map_err(|err| {
+            error!("Failed to spool webhook request: {:?}", err);
+            (StatusCode::SERVICE_UNAVAILABLE, String::from("Unable to store event"))
+        })?;
+        self.notify.notify_one();
+        Ok(StatusCode::ACCEPTED)
+    }
+
+    /// Durably writes `body` to the spool before the sender hears it was accepted. Names sort by
+    /// arrival so events are handed out in order.
+    async fn spool(&self, body: &[u8]) -> Result<()> {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{:016}-{:08}.{}", millis, sequence, SPOOL_EXTENSION);
+        let tmp = self.spool_dir.join(format!(".{}.tmp", name));
+
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(body).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, self.spool_dir.join(name)).await?;
+        // The rename is only durable once the directory entry is
+        sync_dir(&self.spool_dir).await
+    }
+}
+
+/// Flushes a directory's entries, so files renamed into or out of it survive a crash.
+async fn sync_dir(dir: &std::path::Path) -> Result<()> {
+    tokio::fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+/// Runs an HTTP server that accepts file notifications, for stores and services that can call a
+/// webhook but can't publish to a queue.
+pub struct WebhookFileEvents {
+    opts: WebhookFileEventsOptions,
+    local_addr: SocketAddr,
+    server: JoinHandle<()>,
+    notify: Arc<Notify>,
+    in_flight: HashMap<String, SpooledRequest>,
+    queue: RetryQueue,
+}
+
+/// A spooled request whose files are handed out.
+struct SpooledRequest {
+    outstanding: usize,
+    failed: bool,
+}
+
+impl Drop for WebhookFileEvents {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl WebhookFileEvents {
+    pub async fn new(opts: WebhookFileEventsOptions) -> Result<Self> {
+        tokio::fs::create_dir_all(&opts.spool_dir).await?;
+        let notify = Arc::new(Notify::new());
+        let receiver = Arc::new(Receiver {
+            spool_dir: opts.spool_dir.clone(),
+            auth: opts.auth.clone(),
+            max_body_bytes: opts.max_body_bytes,
+            notify: notify.clone(),
+            sequence: AtomicU64::new(0),
+        });
+
+        let make_service = make_service_fn(move |_| {
+            let receiver = receiver.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let receiver = receiver.clone();
+                    async move { Ok::<_, Infallible>(receiver.handle(req).await) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&opts.address)?.serve(make_service);
+        let local_addr = server.local_addr();
+        let server = tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Webhook server stopped: {:?}", err);
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            server,
+            notify,
+            in_flight: HashMap::new(),
+            queue: RetryQueue::new(opts.max_attempts),
+            opts,
+        })
+    }
+
+    /// Where the server is listening, useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn dead_letter_dir(&self) -> PathBuf {
+        self.opts.dead_letter_dir.clone().unwrap_or_else(|| self.opts.spool_dir.join(DEAD_LETTER_DIR))
+    }
+
+    /// Moves a spooled request out of the way, keeping it for inspection or replay.
+    async fn dead_letter(&self, name: &str) -> Result<()> {
+        let dir = self.dead_letter_dir();
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::rename(self.opts.spool_dir.join(name), dir.join(name)).await?;
+        sync_dir(&dir).await?;
+        sync_dir(&self.opts.spool_dir).await
+    }
+
+    /// Spooled requests that aren't already handed out, oldest first.
+    async fn pending(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        let mut entries = tokio::fs::read_dir(&self.opts.spool_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(&format!(".{}", SPOOL_EXTENSION)) && !name.starts_with('.') && !self.in_flight.contains_key(&name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    async fn read_spooled(&mut self) -> Result<()> {
+        for name in self.pending().await? {
+            let path = self.opts.spool_dir.join(&name);
+            let body = tokio::fs::read(&path).await?;
+            match parse_webhook(&body, &AckToken::new(name.clone())) {
+                Ok(events) if events.is_empty() => tokio::fs::remove_file(&path).await?,
+                Ok(events) => {
+                    self.in_flight.insert(
+                        name,
+                        SpooledRequest {
+                            outstanding: events.len(),
+                            failed: false,
+                        },
+                    );
+                    self.queue.extend(events);
+                }
+                Err(err) => {
+                    // Only reachable if the spool was tampered with
+                    error!("Unable to read spooled request {}: {:?}", name, err);
+                    self.dead_letter(&name).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Settles one file of a spooled request. Once all of them are the request is removed, or
+    /// dead lettered if any ran out of attempts.
+    async fn complete(&mut self, token: &AckToken, failed: bool) -> Result<()> {
+        let name = token.as_str();
+        let Some(request) = self.in_flight.get_mut(name) else {
+            return Ok(());
+        };
+        request.outstanding = request.outstanding.saturating_sub(1);
+        request.failed |= failed;
+        if request.outstanding > 0 {
+            return Ok(());
+        }
+        let Some(request) = self.in_flight.remove(name) else {
+            return Ok(());
+        };
+        if request.failed {
+            self.dead_letter(name).await
+        } else {
+            Ok(tokio::fs::remove_file(self.opts.spool_dir.join(name)).await?)
+        }
+    }
+}
+
+impl FileEvents for WebhookFileEvents {
+    async fn next_file(&mut self) -> Result<Vec<FileEvent>> {
+        self.read_spooled().await?;
+        if self.queue.is_empty() && timeout(self.opts.poll_timeout, self.notify.notified()).await.is_ok() {
+            self.read_spooled().await?;
+        }
+        Ok(self.queue.take())
+    }
+
+    async fn ack(&mut self, event: &FileEvent) -> Result<()> {
+        self.queue.ack(event);
+        self.complete(&event.ack_token, false).await
+    }
+
+    async fn nack(&mut self, event: &FileEvent, error: &anyhow::Error) -> Result<()> {
+        let Attempt::Exhausted(attempts) = self.queue.nack(event) else {
+            return Ok(());
+        };
+        warn!("{} from {} failed {} times, dead lettering its request: {:#}", event.path, event.ack_token.as_str(), attempts, error);
+        self.complete(&event.ack_token, true).await
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    async fn start(spool: &TempDir, auth: WebhookAuth) -> Result<WebhookFileEvents> {
+        WebhookFileEvents::new(WebhookFileEventsOptions {
+            address: ([127, 0, 0, 1], 0).into(),
+            spool_dir: spool.path().to_path_buf(),
+            auth,
+            poll_timeout: Duration::from_millis(100),
+            max_attempts: 2,
+            ..Default::default()
+        })
+        .await
+    }
+
+    fn spooled(dir: &std::path::Path) -> Result<usize> {
+        let files = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        Ok(files.iter().filter(|entry| entry.path().is_file()).count())
+    }
+
+    #[test]
+    pub fn test_parse_webhook() -> Result<()> {
+        let token = AckToken::new("1");
+        let minio = std::fs::read("./test_files/minio_object_created.json")?;
+        let events = parse_webhook(&minio, &token)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path.as_ref(), "landing-bucket/year=2023/month=02/part-0001.parquet");
+        assert_eq!(events[0].size, Some(2048));
+
+        let events = parse_webhook(br#"{"path": "s3://landing-bucket/a.parquet"}"#, &token)?;
+        assert_eq!(events[0].path.as_ref(), "landing-bucket/a.parquet");
+        assert_eq!(events[0].source, WEBHOOK_SOURCE);
+        assert!(parse_webhook(br#"{"file": "a.parquet"}"#, &token).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_webhook() -> Result<()> {
+        let spool = TempDir::new()?;
+        let auth = WebhookAuth::HmacSha256 {
+            secret: "hunter2".to_string(),
+            header: "x-signature".to_string(),
+        };
+        let mut events = start(&spool, auth).await?;
+        let url = format!("http://{}/", events.local_addr());
+        let client = reqwest::Client::new();
+
+        let body = std::fs::read("./test_files/minio_object_created.json")?;
+        let rejected = client.post(&url).header("x-signature", sign("wrong", &body)).body(body.clone()).send().await?;
+        assert_eq!(rejected.status(), StatusCode::UNAUTHORIZED);
+        let unreadable = br#"{"file": "a.parquet"}"#.to_vec();
+        let rejected = client.post(&url).header("x-signature", sign("hunter2", &unreadable)).body(unreadable).send().await?;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+        assert!(events.next_file().await?.is_empty());
+
+        let accepted = client.post(&url).header("x-signature", sign("hunter2", &body)).body(body).send().await?;
+        assert_eq!(accepted.status(), StatusCode::ACCEPTED);
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "landing-bucket/year=2023/month=02/part-0001.parquet");
+
+        // A failed file comes back, and its request stays spooled until it is committed
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        assert_eq!(retried[0].path, files[0].path);
+        assert_eq!(spooled(spool.path())?, 1);
+        events.ack(&retried[0]).await?;
+        assert_eq!(spooled(spool.path())?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_dead_letter() -> Result<()> {
+        let spool = TempDir::new()?;
+        let mut events = start(&spool, WebhookAuth::None).await?;
+        let url = format!("http://{}/", events.local_addr());
+        let accepted = reqwest::Client::new().post(&url).body(r#"{"path": "bucket/a.parquet"}"#).send().await?;
+        assert_eq!(accepted.status(), StatusCode::ACCEPTED);
+
+        // Out of attempts, the request is kept in the dead letter directory rather than dropped
+        let files = events.next_file().await?;
+        events.nack(&files[0], &anyhow!("failed")).await?;
+        let retried = events.next_file().await?;
+        events.nack(&retried[0], &anyhow!("failed")).await?;
+        assert_eq!(spooled(spool.path())?, 0);
+        assert_eq!(spooled(&spool.path().join(DEAD_LETTER_DIR))?, 1);
+        assert!(events.next_file().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_spool_survives_restart() -> Result<()> {
+        let spool = TempDir::new()?;
+        let mut events = start(&spool, WebhookAuth::SharedSecret("hunter2".to_string())).await?;
+        let url = format!("http://{}/", events.local_addr());
+        let client = reqwest::Client::new();
+
+        let rejected = client.post(&url).body(r#"{"path": "bucket/a.parquet"}"#).send().await?;
+        assert_eq!(rejected.status(), StatusCode::UNAUTHORIZED);
+        let accepted = client
+            .post(&url)
+            .bearer_auth("hunter2")
+            .body(r#"{"path": "bucket/a.parquet"}"#)
+            .send()
+            .await?;
+        assert_eq!(accepted.status(), StatusCode::ACCEPTED);
+        assert_eq!(events.next_file().await?.len(), 1);
+        drop(events);
+
+        let mut events = start(&spool, WebhookAuth::None).await?;
+        let files = events.next_file().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_ref(), "bucket/a.parquet");
+        Ok(())
+    }
+}